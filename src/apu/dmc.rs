@@ -0,0 +1,151 @@
+// https://wiki.nesdev.org/w/index.php?title=APU_DMC
+// timer periods in CPU cycles (NTSC)
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+#[derive(Debug)]
+pub struct Dmc {
+    pub irq_enabled: bool,
+    pub irq_flag: bool,
+    looping: bool,
+    timer: u16,
+    timer_period: u16,
+    output_level: u8,
+    // memory reader
+    sample_addr: u16,
+    sample_len: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq_flag: false,
+            looping: false,
+            timer: 0,
+            timer_period: RATE_TABLE[0],
+            output_level: 0,
+            sample_addr: 0xc000,
+            sample_len: 1,
+            current_addr: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    // $4010: IL-- RRRR
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.irq_enabled = data & 0b1000_0000 != 0;
+        self.looping = data & 0b0100_0000 != 0;
+        self.timer_period = RATE_TABLE[(data & 0b1111) as usize];
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+    }
+
+    // $4011: -DDD DDDD
+    pub fn write_output_level(&mut self, data: u8) {
+        self.output_level = data & 0b0111_1111;
+    }
+
+    // $4012: sample address = $C000 + A * 64
+    pub fn write_sample_addr(&mut self, data: u8) {
+        self.sample_addr = 0xc000 | ((data as u16) << 6);
+    }
+
+    // $4013: sample length = L * 16 + 1
+    pub fn write_sample_len(&mut self, data: u8) {
+        self.sample_len = ((data as u16) << 4) | 1;
+    }
+
+    // bit 4 of $4015
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    // address the memory reader wants to fetch, if the sample buffer needs refilling
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    pub fn dma_complete(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_addr = if self.current_addr == 0xffff {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
@@ -0,0 +1,63 @@
+// volume envelope shared by the pulse and noise channels
+// https://wiki.nesdev.org/w/index.php?title=APU_Envelope
+#[derive(Debug)]
+pub struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+    period: u8,
+    pub looping: bool,
+    constant: bool,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            divider: 0,
+            decay: 0,
+            period: 0,
+            looping: false,
+            constant: false,
+        }
+    }
+
+    // --LC VVVV
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.period = data & 0b1111;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // clocked by the frame counter every quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looping {
+            self.decay = 15;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// https://wiki.nesdev.org/w/index.php?title=APU_Frame_Counter
+
+// CPU cycles at which each step of the sequence happens
+pub const STEP_CYCLES: [usize; 5] = [7457, 14913, 22371, 29829, 37281];
+const FOUR_STEP_LEN: usize = 29830;
+const FIVE_STEP_LEN: usize = 37282;
+
+#[derive(Debug, PartialEq)]
+pub enum Clock {
+    None,
+    // envelopes and the triangle linear counter
+    Quarter,
+    // quarter frame units plus length counters and sweeps
+    Half,
+}
+
+#[derive(Debug)]
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    pub irq_flag: bool,
+    // CPU cycles since the sequence started
+    cycles: usize,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        FrameCounter {
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycles: 0,
+        }
+    }
+
+    // $4017: MI-- ----
+    pub fn write(&mut self, data: u8) -> Clock {
+        self.five_step = data & 0b1000_0000 != 0;
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        self.cycles = 0;
+        // 5-step mode clocks all units immediately
+        if self.five_step {
+            Clock::Half
+        } else {
+            Clock::None
+        }
+    }
+
+    pub fn tick(&mut self) -> Clock {
+        self.cycles += 1;
+        let clock = match (self.five_step, self.cycles) {
+            (_, c) if c == STEP_CYCLES[0] || c == STEP_CYCLES[2] => Clock::Quarter,
+            (_, c) if c == STEP_CYCLES[1] => Clock::Half,
+            (false, c) if c == STEP_CYCLES[3] => {
+                if !self.irq_inhibit {
+                    self.irq_flag = true;
+                }
+                Clock::Half
+            },
+            (true, c) if c == STEP_CYCLES[4] => Clock::Half,
+            _ => Clock::None,
+        };
+        let len = if self.five_step { FIVE_STEP_LEN } else { FOUR_STEP_LEN };
+        if self.cycles >= len {
+            self.cycles = 0;
+        }
+        clock
+    }
+}
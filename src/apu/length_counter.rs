@@ -0,0 +1,48 @@
+// https://wiki.nesdev.org/w/index.php?title=APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[derive(Debug)]
+pub struct LengthCounter {
+    enabled: bool,
+    pub halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    // channel enable bit of $4015, disabling silences the channel immediately
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // upper 5 bits of the channel's last register
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    // clocked by the frame counter every half frame
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
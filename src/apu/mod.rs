@@ -0,0 +1,534 @@
+mod envelope;
+mod length_counter;
+mod pulse;
+mod triangle;
+mod noise;
+mod dmc;
+mod frame_counter;
+
+use self::frame_counter::{Clock, FrameCounter};
+use std::fmt::Debug;
+
+// APU registers
+//  $4000 ~ $4003  Pulse 1
+//  $4004 ~ $4007  Pulse 2
+//  $4008 ~ $400B  Triangle
+//  $400C ~ $400F  Noise
+//  $4010 ~ $4013  DMC
+//  $4015          Status (channel enable / length counter status)
+//  $4017          Frame counter (write only, reads go to joypad 2)
+
+pub const CPU_CLOCK: u32 = 1_789_773;
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// stereo position of each channel, -1.0 (left) to 1.0 (right)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Panning {
+    pub pulse1: f32,
+    pub pulse2: f32,
+    pub triangle: f32,
+    pub noise: f32,
+    pub dmc: f32,
+}
+
+impl Panning {
+    // pulse 1 on the left, pulse 2 on the right, the rest in the middle
+    pub fn split_pulses() -> Self {
+        Panning {
+            pulse1: -0.5,
+            pulse2: 0.5,
+            triangle: 0.0,
+            noise: 0.0,
+            dmc: 0.0,
+        }
+    }
+
+    // "P1,P2,TRI,NOISE,DMC"
+    pub fn parse(s: &str) -> Result<Panning, String> {
+        let values: Vec<f32> = s.split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid panning `{}`", s))?;
+        if values.len() != 5 || values.iter().any(|v| *v < -1.0 || *v > 1.0) {
+            return Err(format!("panning needs 5 values between -1 and 1: `{}`", s));
+        }
+        Ok(Panning {
+            pulse1: values[0],
+            pulse2: values[1],
+            triangle: values[2],
+            noise: values[3],
+            dmc: values[4],
+        })
+    }
+
+    // gains for one side, a centered channel keeps its full level on both sides
+    fn gains(&self, right: bool) -> [f32; 5] {
+        let gain = |pan: f32| if right { (1.0 + pan).min(1.0) } else { (1.0 - pan).min(1.0) };
+        [gain(self.pulse1), gain(self.pulse2), gain(self.triangle), gain(self.noise), gain(self.dmc)]
+    }
+}
+
+#[derive(Debug)]
+pub struct Apu {
+    pulse1: pulse::Pulse,
+    pulse2: pulse::Pulse,
+    triangle: triangle::Triangle,
+    noise: noise::Noise,
+    dmc: dmc::Dmc,
+    frame_counter: FrameCounter,
+    odd_cycle: bool,
+    // downsample from CPU clock to SAMPLE_RATE
+    sample_clock: u32,
+    // interleaved left/right when set
+    panning: Option<Panning>,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse1: pulse::Pulse::new(true),
+            pulse2: pulse::Pulse::new(false),
+            triangle: triangle::Triangle::new(),
+            noise: noise::Noise::new(),
+            dmc: dmc::Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            odd_cycle: false,
+            sample_clock: 0,
+            panning: None,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000 => self.pulse1.write_ctrl(data),
+            0x4001 => self.pulse1.write_sweep(data),
+            0x4002 => self.pulse1.write_timer_low(data),
+            0x4003 => self.pulse1.write_timer_high(data),
+            0x4004 => self.pulse2.write_ctrl(data),
+            0x4005 => self.pulse2.write_sweep(data),
+            0x4006 => self.pulse2.write_timer_low(data),
+            0x4007 => self.pulse2.write_timer_high(data),
+            0x4008 => self.triangle.write_linear_counter(data),
+            0x400a => self.triangle.write_timer_low(data),
+            0x400b => self.triangle.write_timer_high(data),
+            0x400c => self.noise.write_ctrl(data),
+            0x400e => self.noise.write_period(data),
+            0x400f => self.noise.write_length(data),
+            0x4010 => self.dmc.write_ctrl(data),
+            0x4011 => self.dmc.write_output_level(data),
+            0x4012 => self.dmc.write_sample_addr(data),
+            0x4013 => self.dmc.write_sample_len(data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            },
+            0x4017 => {
+                let clock = self.frame_counter.write(data);
+                self.clock_frame_units(clock);
+            },
+            _ => (),
+        }
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        // reading acknowledges the frame interrupt
+        self.frame_counter.irq_flag = false;
+        status
+    }
+
+    // what read_status would return, without acknowledging the frame interrupt
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length.is_active() {
+            status |= 0b01;
+        }
+        if self.pulse2.length.is_active() {
+            status |= 0b10;
+        }
+        if self.triangle.length.is_active() {
+            status |= 0b100;
+        }
+        if self.noise.length.is_active() {
+            status |= 0b1000;
+        }
+        if self.dmc.is_active() {
+            status |= 0b1_0000;
+        }
+        if self.frame_counter.irq_flag {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq_flag {
+            status |= 0b1000_0000;
+        }
+        status
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.tick_cycle();
+        }
+    }
+
+    fn tick_cycle(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        // pulse timers run at half the CPU clock
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        let clock = self.frame_counter.tick();
+        self.clock_frame_units(clock);
+
+        self.sample_clock += SAMPLE_RATE;
+        if self.sample_clock >= CPU_CLOCK {
+            self.sample_clock -= CPU_CLOCK;
+            match self.panning {
+                Some(panning) => {
+                    let left = self.mix(panning.gains(false));
+                    let right = self.mix(panning.gains(true));
+                    self.samples.push(left);
+                    self.samples.push(right);
+                },
+                None => {
+                    let sample = self.mix([1.0; 5]);
+                    self.samples.push(sample);
+                },
+            }
+        }
+    }
+
+    fn clock_frame_units(&mut self, clock: Clock) {
+        match clock {
+            Clock::None => (),
+            Clock::Quarter => self.clock_quarter_frame(),
+            Clock::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // https://wiki.nesdev.org/w/index.php?title=APU_Mixer
+    // gains scale each channel (pulse 1, pulse 2, triangle, noise, DMC) before mixing
+    fn mix(&self, gains: [f32; 5]) -> f32 {
+        let pulse = self.pulse1.output() as f32 * gains[0] + self.pulse2.output() as f32 * gains[1];
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 * gains[2] / 8227.0
+            + self.noise.output() as f32 * gains[3] / 12241.0
+            + self.dmc.output() as f32 * gains[4] / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    // level of the APU's IRQ output, stays asserted until acknowledged
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    // the DMC memory reader fetches sample bytes through the bus
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc.dma_complete(data);
+    }
+
+    // None mixes down to mono
+    pub fn set_panning(&mut self, panning: Option<Panning>) {
+        self.panning = panning;
+    }
+
+    pub fn channels(&self) -> u8 {
+        if self.panning.is_some() { 2 } else { 1 }
+    }
+
+    // samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+// the APU as the CPU bus sees it: registers at $4000-$4017, DMC fetches, IRQ and the clock
+// Apu is the real one, BusBuilder::apu takes another, e.g. a silent stub in tests
+pub trait ApuDevice: Debug {
+    // addr is $4000-$4013, $4015 or $4017
+    fn write_register(&mut self, addr: u16, data: u8);
+    // $4015, reading acknowledges the frame IRQ
+    fn read_status(&mut self) -> u8;
+    fn peek_status(&self) -> u8;
+    // CPU cycles
+    fn tick(&mut self, cycles: u8);
+    fn irq(&self) -> bool {
+        false
+    }
+    // address of the sample byte the DMC wants fetched
+    fn dmc_dma_request(&self) -> Option<u16> {
+        None
+    }
+    fn dmc_dma_complete(&mut self, _data: u8) {}
+    // the real APU for the frame callback and settings, None for stand-ins
+    fn as_apu_mut(&mut self) -> Option<&mut Apu> {
+        None
+    }
+}
+
+impl ApuDevice for Apu {
+    fn write_register(&mut self, addr: u16, data: u8) {
+        Apu::write_register(self, addr, data);
+    }
+
+    fn read_status(&mut self) -> u8 {
+        Apu::read_status(self)
+    }
+
+    fn peek_status(&self) -> u8 {
+        Apu::peek_status(self)
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        Apu::tick(self, cycles);
+    }
+
+    fn irq(&self) -> bool {
+        Apu::irq(self)
+    }
+
+    fn dmc_dma_request(&self) -> Option<u16> {
+        Apu::dmc_dma_request(self)
+    }
+
+    fn dmc_dma_complete(&mut self, data: u8) {
+        Apu::dmc_dma_complete(self, data);
+    }
+
+    fn as_apu_mut(&mut self) -> Option<&mut Apu> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use apu::frame_counter::STEP_CYCLES;
+
+    #[test]
+    fn test_pulse_length_counter_status() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4003, 0b0000_1000); // length index 1 = 254, ignored while disabled
+        assert_eq!(apu.read_status() & 1, 0);
+
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b0000_0000);
+        apu.write_register(0x4003, 0b0001_1000); // length index 3 = 2
+        assert_eq!(apu.read_status() & 1, 1);
+
+        // two half frames empty the counter
+        for _ in 0..STEP_CYCLES[3] {
+            apu.tick(1);
+        }
+        assert_eq!(apu.read_status() & 1, 0);
+
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_pulse_produces_samples() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b1011_1111); // 50% duty, constant volume 15
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0b0000_1000); // period 0x0fd ~= 440Hz
+        for _ in 0..(CPU_CLOCK / 60) {
+            apu.tick(1);
+        }
+        let samples = apu.take_samples();
+        assert!((samples.len() as i32 - (SAMPLE_RATE / 60) as i32).abs() <= 1);
+        // the idle triangle channel adds a constant offset, so look for changes instead of zeros
+        assert!(samples.windows(2).any(|w| w[0] != w[1]));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_triangle_needs_linear_counter() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0100);
+        apu.write_register(0x4008, 0b0000_0000); // linear counter reload 0
+        apu.write_register(0x400a, 0x40);
+        apu.write_register(0x400b, 0b0000_1000);
+        for _ in 0..STEP_CYCLES[0] + 1000 {
+            apu.tick(1);
+        }
+        // sequencer stays at its first step
+        assert!(apu.take_samples().windows(2).all(|w| w[0] == w[1]));
+
+        apu.write_register(0x4008, 0b0111_1111);
+        apu.write_register(0x400b, 0b0000_1000);
+        for _ in 0..STEP_CYCLES[0] + 1000 {
+            apu.tick(1);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.windows(2).any(|w| w[0] != w[1]));
+        assert_eq!(apu.read_status() & 0b0100, 0b0100);
+    }
+
+    #[test]
+    fn test_noise_status_and_output() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b1000);
+        apu.write_register(0x400c, 0b0011_1111); // constant volume 15, halt length
+        apu.write_register(0x400e, 0x03);
+        apu.write_register(0x400f, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0b1000, 0b1000);
+        for _ in 0..10000 {
+            apu.tick(1);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.windows(2).any(|w| w[0] != w[1]));
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_stereo_panning() {
+        let mut apu = Apu::new();
+        apu.set_panning(Some(Panning::parse("-1,1,0,0,0").unwrap()));
+        assert_eq!(apu.channels(), 2);
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0b0000_1000);
+        for _ in 0..CPU_CLOCK / 60 {
+            apu.tick(1);
+        }
+        let samples = apu.take_samples();
+        assert_eq!(samples.len() % 2, 0);
+        let left: Vec<f32> = samples.iter().step_by(2).cloned().collect();
+        let right: Vec<f32> = samples.iter().skip(1).step_by(2).cloned().collect();
+        // pulse 1 is hard left
+        assert!(left.windows(2).any(|w| w[0] != w[1]));
+        assert!(right.windows(2).all(|w| w[0] == w[1]));
+
+        assert!(Panning::parse("0,0,0").is_err());
+        assert!(Panning::parse("0,0,0,0,2").is_err());
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        for _ in 0..STEP_CYCLES[3] - 1 {
+            apu.tick(1);
+        }
+        assert!(!apu.irq());
+        apu.tick(1);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        // acknowledged by the read
+        assert!(!apu.irq());
+
+        // inhibited
+        apu.write_register(0x4017, 0b0100_0000);
+        for _ in 0..STEP_CYCLES[4] {
+            apu.tick(1);
+        }
+        assert!(!apu.irq());
+
+        // no IRQ in 5-step mode
+        apu.write_register(0x4017, 0b1000_0000);
+        for _ in 0..STEP_CYCLES[4] * 2 {
+            apu.tick(1);
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_five_step_mode_clocks_immediately() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b0000_0000);
+        apu.write_register(0x4003, 0b0001_1000); // length 2
+        apu.write_register(0x4017, 0b1000_0000);
+        for _ in 0..STEP_CYCLES[1] {
+            apu.tick(1);
+        }
+        assert_eq!(apu.read_status() & 1, 0);
+    }
+
+    #[test]
+    fn test_dmc_memory_reader() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4010, 0b1000_1111); // IRQ, fastest rate
+        apu.write_register(0x4012, 0x01); // $C040
+        apu.write_register(0x4013, 0x00); // 1 byte
+        assert_eq!(apu.dmc_dma_request(), None);
+
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.read_status() & 0b1_0000, 0b1_0000);
+        assert_eq!(apu.dmc_dma_request(), Some(0xc040));
+
+        apu.dmc_dma_complete(0xff);
+        assert_eq!(apu.dmc_dma_request(), None);
+        assert_eq!(apu.read_status(), 0b1000_0000);
+
+        // all ones raise the output level by 2 per bit
+        for _ in 0..54 * 16 {
+            apu.tick(1);
+        }
+        assert_eq!(apu.dmc.output(), 16);
+
+        // enabling again restarts the sample and acknowledges the IRQ
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.read_status(), 0b1_0000);
+        assert_eq!(apu.dmc_dma_request(), Some(0xc040));
+    }
+
+    #[test]
+    fn test_pulse_muted_by_low_period() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b01);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0x07);
+        apu.write_register(0x4003, 0b0000_1000);
+        for _ in 0..1000 {
+            apu.tick(1);
+        }
+        assert!(apu.take_samples().windows(2).all(|w| w[0] == w[1]));
+    }
+}
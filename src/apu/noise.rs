@@ -0,0 +1,70 @@
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+
+// https://wiki.nesdev.org/w/index.php?title=APU_Noise
+// timer periods in CPU cycles (NTSC)
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+#[derive(Debug)]
+pub struct Noise {
+    // 15 bit linear feedback shift register
+    shift: u16,
+    short_mode: bool,
+    timer: u16,
+    timer_period: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            shift: 1,
+            short_mode: false,
+            timer: 0,
+            timer_period: PERIOD_TABLE[0],
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // $400C: --LC VVVV
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.envelope.write(data);
+        self.length.halt = self.envelope.looping;
+    }
+
+    // $400E: M--- PPPP
+    pub fn write_period(&mut self, data: u8) {
+        self.short_mode = data & 0b1000_0000 != 0;
+        self.timer_period = PERIOD_TABLE[(data & 0b1111) as usize];
+    }
+
+    // $400F: LLLL L---
+    pub fn write_length(&mut self, data: u8) {
+        self.length.load(data);
+        self.envelope.restart();
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift & 1) ^ ((self.shift >> tap) & 1);
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.shift & 1 == 1 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
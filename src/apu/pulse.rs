@@ -0,0 +1,122 @@
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+
+// https://wiki.nesdev.org/w/index.php?title=APU_Pulse
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[derive(Debug)]
+pub struct Pulse {
+    // pulse 1 negates with one's complement, pulse 2 with two's complement
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    timer: u16,
+    timer_period: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            step: 0,
+            timer: 0,
+            timer_period: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // $4000 / $4004: DDLC VVVV
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.duty = data >> 6;
+        self.envelope.write(data);
+        self.length.halt = self.envelope.looping;
+    }
+
+    // $4001 / $4005: EPPP NSSS
+    pub fn write_sweep(&mut self, data: u8) {
+        self.sweep_enabled = data & 0b1000_0000 != 0;
+        self.sweep_period = (data >> 4) & 0b111;
+        self.sweep_negate = data & 0b1000 != 0;
+        self.sweep_shift = data & 0b111;
+        self.sweep_reload = true;
+    }
+
+    // $4002 / $4006: timer low
+    pub fn write_timer_low(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0xff00) | data as u16;
+    }
+
+    // $4003 / $4007: LLLL LHHH
+    pub fn write_timer_high(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+        self.length.load(data);
+        self.step = 0;
+        self.envelope.restart();
+    }
+
+    // clocked every APU cycle (2 CPU cycles)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = if self.ones_complement { change + 1 } else { change };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7ff
+    }
+
+    // clocked by the frame counter every half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.is_muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use apu::length_counter::LengthCounter;
+
+// https://wiki.nesdev.org/w/index.php?title=APU_Triangle
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Debug)]
+pub struct Triangle {
+    step: u8,
+    timer: u16,
+    timer_period: u16,
+    pub length: LengthCounter,
+    // control flag doubles as the length counter halt
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            step: 0,
+            timer: 0,
+            timer_period: 0,
+            length: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+        }
+    }
+
+    // $4008: CRRR RRRR
+    pub fn write_linear_counter(&mut self, data: u8) {
+        self.control = data & 0b1000_0000 != 0;
+        self.length.halt = self.control;
+        self.linear_reload_value = data & 0b0111_1111;
+    }
+
+    // $400A: timer low
+    pub fn write_timer_low(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0xff00) | data as u16;
+    }
+
+    // $400B: LLLL LHHH
+    pub fn write_timer_high(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+        self.length.load(data);
+        self.linear_reload = true;
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // periods below 2 are ultrasonic and only produce popping, hold the output instead
+            if self.linear_counter > 0 && self.length.is_active() && self.timer_period >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // clocked by the frame counter every quarter frame
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // the sequencer is halted rather than silenced, so output is never forced to 0
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
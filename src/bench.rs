@@ -0,0 +1,188 @@
+// synthetic ROMs for benchmarking the CPU dispatcher
+// every program loops forever, so runs only differ by how many instructions are executed
+
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+use ines;
+use cartridge;
+use memory;
+use cpu;
+use ppu;
+use apu;
+use controller;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mix {
+    // register and immediate arithmetic only
+    Alu,
+    // zero page and absolute indexed loads/stores
+    Memory,
+    // tight counted loops, taken and untaken branches
+    Branch,
+}
+
+impl Mix {
+    pub fn from_name(name: &str) -> Option<Mix> {
+        match name {
+            "alu" => Some(Mix::Alu),
+            "memory" => Some(Mix::Memory),
+            "branch" => Some(Mix::Branch),
+            _ => None,
+        }
+    }
+}
+
+// the loop body, assembled at $8000
+fn body(mix: Mix) -> Vec<u8> {
+    match mix {
+        Mix::Alu => vec![
+            0x18,             // CLC
+            0xa9, 0x37,       // LDA #$37
+            0x69, 0x5a,       // ADC #$5A
+            0x49, 0xff,       // EOR #$FF
+            0x29, 0x7e,       // AND #$7E
+            0x09, 0x81,       // ORA #$81
+            0x38,             // SEC
+            0xe9, 0x13,       // SBC #$13
+            0xc9, 0x40,       // CMP #$40
+            0xaa,             // TAX
+            0xe8,             // INX
+            0x98,             // TYA
+            0x88,             // DEY
+        ],
+        Mix::Memory => vec![
+            0xa2, 0x00,       // LDX #$00
+            0xbd, 0x00, 0x02, // $8002: LDA $0200,X
+            0x9d, 0x00, 0x03, // STA $0300,X
+            0xe6, 0x10,       // INC $10
+            0xa5, 0x10,       // LDA $10
+            0x85, 0x11,       // STA $11
+            0xe8,             // INX
+            0xd0, 0xf1,       // BNE $8002
+        ],
+        Mix::Branch => vec![
+            0xa2, 0x00,       // LDX #$00
+            0xa0, 0x04,       // $8002: LDY #$04
+            0x88,             // $8004: DEY
+            0xd0, 0xfd,       // BNE $8004
+            0x18,             // CLC
+            0x90, 0x00,       // BCC (taken)
+            0xb0, 0x00,       // BCS (not taken)
+            0x38,             // SEC
+            0xb0, 0x00,       // BCS (taken)
+            0xca,             // DEX
+            0xd0, 0xf0,       // BNE $8002
+        ],
+    }
+}
+
+// body followed by JMP $8000
+pub fn program(mix: Mix) -> Vec<u8> {
+    let mut prg = body(mix);
+    prg.extend(&[0x4c, 0x00, 0x80]);
+    prg
+}
+
+// NROM-256 image with the reset vector at $8000
+pub fn generate(mix: Mix) -> Vec<u8> {
+    let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    let mut prg_rom = vec![0; 0x8000];
+    let prg = program(mix);
+    prg_rom[..prg.len()].copy_from_slice(&prg);
+    prg_rom[0x7ffc] = 0x00;
+    prg_rom[0x7ffd] = 0x80;
+    raw.extend(prg_rom);
+    raw.extend(vec![0; 0x2000]);
+    raw
+}
+
+// nes-emu bench-rom alu|memory|branch <out path>
+pub fn run_generate(args: &[String]) -> ! {
+    let mix = args.get(2).and_then(|name| Mix::from_name(name));
+    match (mix, args.get(3)) {
+        (Some(mix), Some(path)) => {
+            File::create(path).unwrap().write_all(&generate(mix)).unwrap();
+        },
+        _ => println!("usage: nes-emu bench-rom alu|memory|branch <out path>"),
+    }
+    std::process::exit(0);
+}
+
+// nes-emu bench <file path> [instructions]
+pub fn run(args: &[String]) -> ! {
+    let path = match args.get(2) {
+        Some(path) => path,
+        None => {
+            println!("usage: nes-emu bench <file path> [instructions]");
+            std::process::exit(0);
+        }
+    };
+    let limit: u64 = args.get(3).map(|n| n.parse().unwrap()).unwrap_or(10_000_000);
+    let raw = std::fs::read(path).unwrap();
+    let rom = ines::Rom::analyze_raw(&raw).unwrap();
+    if !cartridge::is_supported(rom.mapper) {
+        println!("mapper {} is not supported", rom.mapper);
+        std::process::exit(1);
+    }
+    let bus = memory::Bus::new(rom, |_ppu: &ppu::Ppu, _apu: &mut apu::Apu, _joypads: &mut [controller::Joypad; 2]| {});
+    let mut cpu = cpu::Cpu::new(bus);
+    cpu.reset();
+    // no video or audio, only CPU/PPU/APU stepping is measured
+    let begin = Instant::now();
+    let mut count: u64 = 0;
+    cpu.run_with_callback(|cpu| {
+        count += 1;
+        if count == limit {
+            cpu.halt();
+        }
+    });
+    let elapsed = begin.elapsed();
+    println!("{} instructions, {} frames in {:.3}s ({:.2} MIPS)",
+        count,
+        cpu.bus.frame_count(),
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64() / 1e6);
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::Cpu;
+    use memory::Bus;
+    use ppu::Ppu;
+    use apu::Apu;
+    use controller::Joypad;
+    use ines::Rom;
+    use ines::test::TestRomBuilder;
+
+    #[test]
+    fn test_generated_rom() {
+        let rom = Rom::analyze_raw(&generate(Mix::Branch)).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(&rom.prg_rom[0..2], &[0xa2, 0x00]);
+        assert_eq!(&rom.prg_rom[0x7ffc..0x7ffe], &[0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_bodies_terminate() {
+        // replace the JMP with BRK to stop after a single iteration
+        for mix in [Mix::Alu, Mix::Memory, Mix::Branch].iter() {
+            let mut prg = body(*mix);
+            let end = 0x8000 + prg.len() as u16;
+            prg.push(0x00);
+            let rom = TestRomBuilder::new().prg(&prg).build();
+            let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+            let mut cpu = Cpu::new(bus);
+            cpu.reset();
+            let mut count = 0;
+            cpu.run_until_brk(|cpu| {
+                assert!(cpu.pc >= 0x8000 && cpu.pc <= end);
+                count += 1;
+            });
+            assert!(count >= body(*mix).len() / 3, "{:?}", mix);
+        }
+    }
+}
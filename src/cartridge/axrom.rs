@@ -0,0 +1,84 @@
+use cartridge::Mapper;
+use ppu::Mirroring;
+
+// mapper 7
+// https://wiki.nesdev.org/w/index.php?title=AxROM
+// $8000-$FFFF: ---M -PPP
+//   M: nametable used for all four screens
+//   PPP: 32KB PRG bank
+// CHR is always 8KB of RAM
+#[derive(Debug)]
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr_ram: Vec<u8>,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl Axrom {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Axrom {
+            prg_rom,
+            chr_ram: vec![0; 0x2000],
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenA,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let banks = (self.prg_rom.len() / 0x8000).max(1);
+        let offset = (self.prg_bank % banks) * 0x8000 + (addr - 0x8000) as usize;
+        self.prg_rom[offset % self.prg_rom.len()]
+    }
+
+    fn prg_write(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = (data & 0b111) as usize;
+        self.mirroring = if data & 0b1_0000 == 0 {
+            Mirroring::SingleScreenA
+        } else {
+            Mirroring::SingleScreenB
+        };
+    }
+
+    fn chr_read(&self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bank_switching() {
+        let mut prg_rom = vec![0; 0x8000 * 4];
+        for bank in 0..4 {
+            prg_rom[bank * 0x8000 + 0x10] = bank as u8;
+        }
+        let mut axrom = Axrom::new(prg_rom);
+        assert_eq!(axrom.prg_read(0x8010), 0);
+        axrom.prg_write(0x8000, 0b0001_0010);
+        assert_eq!(axrom.prg_read(0x8010), 2);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenB);
+        axrom.prg_write(0xffff, 0b0000_0011);
+        assert_eq!(axrom.prg_read(0x8010), 3);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenA);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut axrom = Axrom::new(vec![0; 0x8000]);
+        axrom.chr_write(0x1234, 0x56);
+        assert_eq!(axrom.chr_read(0x1234), 0x56);
+    }
+}
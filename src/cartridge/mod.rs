@@ -0,0 +1,44 @@
+mod nrom;
+mod axrom;
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::rc::Rc;
+use ines::Rom;
+use ppu::Mirroring;
+
+// Everything on the cartridge side of the bus: PRG at $8000-$FFFF on the CPU
+// and CHR at $0000-$1FFF on the PPU, plus the nametable mirroring it wires up.
+// Addresses are passed as seen by the CPU/PPU, bank switching is up to the mapper.
+pub trait Mapper: Debug {
+    fn prg_read(&self, addr: u16) -> u8;
+    fn prg_write(&mut self, addr: u16, data: u8);
+    fn chr_read(&self, addr: u16) -> u8;
+    fn chr_write(&mut self, addr: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+    // level of the cartridge's IRQ output
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+// shared by the CPU bus and the PPU
+pub type Cartridge = Rc<RefCell<Box<dyn Mapper>>>;
+
+pub fn is_supported(mapper: u8) -> bool {
+    matches!(mapper, 0 | 7)
+}
+
+impl Rom {
+    pub fn into_mapper(self) -> Result<Box<dyn Mapper>, String> {
+        match self.mapper {
+            0 => Ok(Box::new(nrom::Nrom::new(self.prg_rom, self.chr_rom, self.chr_ram, self.mirroring))),
+            7 => Ok(Box::new(axrom::Axrom::new(self.prg_rom))),
+            n => Err(format!("mapper {} is not supported", n)),
+        }
+    }
+
+    pub fn into_cartridge(self) -> Result<Cartridge, String> {
+        self.into_mapper().map(|mapper| Rc::new(RefCell::new(mapper)))
+    }
+}
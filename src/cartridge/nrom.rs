@@ -0,0 +1,74 @@
+use cartridge::Mapper;
+use ppu::Mirroring;
+
+// mapper 0
+// https://wiki.nesdev.org/w/index.php?title=NROM
+// NROM-128 (16KB PRG) is mirrored into $C000-$FFFF
+#[derive(Debug)]
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, chr_ram: bool, mirroring: Mirroring) -> Self {
+        Nrom {
+            prg_rom,
+            chr_rom,
+            chr_ram,
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let mut addr = addr - 0x8000;
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            addr %= 0x4000;
+        }
+        self.prg_rom[addr as usize]
+    }
+
+    fn prg_write(&mut self, addr: u16, _data: u8) {
+        panic!("cannot write to program ROM: 0x{:X}", addr);
+    }
+
+    fn chr_read(&self, addr: u16) -> u8 {
+        self.chr_rom[addr as usize]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if !self.chr_ram {
+            panic!("cannot write to character ROM: 0x{:X}", addr);
+        }
+        self.chr_rom[addr as usize] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nrom_128_mirroring() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0x3ffc] = 0x12;
+        let nrom = Nrom::new(prg_rom, vec![0; 0x2000], false, Mirroring::Vertical);
+        assert_eq!(nrom.prg_read(0xbffc), 0x12);
+        assert_eq!(nrom.prg_read(0xfffc), 0x12);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut nrom = Nrom::new(vec![0; 0x4000], vec![0; 0x2000], true, Mirroring::Vertical);
+        nrom.chr_write(0x1ff0, 0xab);
+        assert_eq!(nrom.chr_read(0x1ff0), 0xab);
+    }
+}
@@ -0,0 +1,129 @@
+// RAM predicates evaluated once per frame
+// a condition fires on the frame it becomes true, not on every frame it holds
+
+#[derive(Debug, PartialEq)]
+pub enum Condition {
+    // ADDR==VALUE
+    Equals(u16, u8),
+    // ADDR>=VALUE: value crossed the threshold upwards
+    Crossed(u16, u8),
+    // ADDR+=N / ADDR-=N: value changed by N since the previous frame
+    Delta(u16, i16),
+}
+
+impl Condition {
+    pub fn parse(expr: &str) -> Result<Condition, String> {
+        let ops = ["==", ">=", "+=", "-="];
+        let (pos, op) = ops.iter()
+            .filter_map(|op| expr.find(op).map(|pos| (pos, *op)))
+            .min()
+            .ok_or(format!("no operator in condition `{}`", expr))?;
+        let addr = parse_number(&expr[..pos])?;
+        let value = parse_number(&expr[pos + 2..])?;
+        if addr > 0x1fff {
+            return Err(format!("condition address 0x{:X} is not in RAM", addr));
+        }
+        if value > 0xff {
+            return Err(format!("condition value {} does not fit in a byte", value));
+        }
+        let addr = addr as u16;
+        Ok(match op {
+            "==" => Condition::Equals(addr, value as u8),
+            ">=" => Condition::Crossed(addr, value as u8),
+            "+=" => Condition::Delta(addr, value as i16),
+            _ => Condition::Delta(addr, -(value as i16)),
+        })
+    }
+
+    pub fn addr(&self) -> u16 {
+        match *self {
+            Condition::Equals(addr, _) | Condition::Crossed(addr, _) | Condition::Delta(addr, _) => addr,
+        }
+    }
+
+    fn holds(&self, prev: Option<u8>, cur: u8) -> bool {
+        match *self {
+            Condition::Equals(_, value) => cur == value,
+            Condition::Crossed(_, value) => cur >= value,
+            Condition::Delta(_, delta) => match prev {
+                Some(prev) => cur as i16 - prev as i16 == delta,
+                None => false,
+            },
+        }
+    }
+}
+
+pub fn parse_number(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let hex = s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .or_else(|| s.strip_prefix('$'));
+    let res = match hex {
+        Some(digits) => u32::from_str_radix(digits, 16),
+        None => s.parse(),
+    };
+    res.map_err(|_| format!("invalid number `{}`", s))
+}
+
+pub struct Watch {
+    pub expr: String,
+    pub condition: Condition,
+    prev: Option<u8>,
+    active: bool,
+}
+
+impl Watch {
+    pub fn new(expr: &str) -> Result<Watch, String> {
+        Ok(Watch {
+            expr: expr.to_string(),
+            condition: Condition::parse(expr)?,
+            prev: None,
+            active: false,
+        })
+    }
+
+    // feed this frame's value, returns true when the condition fires
+    pub fn update(&mut self, value: u8) -> bool {
+        let holds = self.condition.holds(self.prev, value);
+        let fired = holds && !self.active;
+        self.active = holds;
+        self.prev = Some(value);
+        fired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Condition::parse("0x75==3"), Ok(Condition::Equals(0x75, 3)));
+        assert_eq!(Condition::parse("$07fa>=$10"), Ok(Condition::Crossed(0x7fa, 0x10)));
+        assert_eq!(Condition::parse("16+=1"), Ok(Condition::Delta(16, 1)));
+        assert_eq!(Condition::parse("16-=2"), Ok(Condition::Delta(16, -2)));
+        assert!(Condition::parse("0x75").is_err());
+        assert!(Condition::parse("0x8000==1").is_err());
+        assert!(Condition::parse("0x75==256").is_err());
+    }
+
+    #[test]
+    fn test_fires_on_edge() {
+        let mut watch = Watch::new("0x10>=5").unwrap();
+        assert!(!watch.update(4));
+        assert!(watch.update(5));
+        assert!(!watch.update(6));
+        assert!(!watch.update(1));
+        assert!(watch.update(9));
+    }
+
+    #[test]
+    fn test_delta() {
+        let mut watch = Watch::new("0x10-=1").unwrap();
+        assert!(!watch.update(3));
+        assert!(watch.update(2));
+        assert!(!watch.update(1));
+        assert!(!watch.update(1));
+        assert!(watch.update(0));
+    }
+}
@@ -18,6 +18,8 @@ pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_stat: JoypadButton,
+    // the Famicom's controller 2 has a microphone instead of select/start
+    microphone: bool,
 }
 
 impl Joypad {
@@ -26,6 +28,7 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_stat: JoypadButton::from_bits_truncate(0),
+            microphone: false,
         }
     }
 
@@ -37,19 +40,33 @@ impl Joypad {
     }
 
     pub fn read(&mut self) -> u8 {
-        if self.button_index > 7  {
-            return 1;
-        }
-        let response = (self.button_stat.bits & (1 << self.button_index)) >> self.button_index;
+        let response = self.peek();
         if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
         response
     }
 
+    // the bit read would return, without shifting to the next button
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7  {
+            return 1;
+        }
+        (self.button_stat.bits & (1 << self.button_index)) >> self.button_index
+    }
+
     pub fn set_button_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_stat.set(button, pressed);
     }
+
+    pub fn set_microphone(&mut self, loud: bool) {
+        self.microphone = loud;
+    }
+
+    // the microphone level is read at $4016 bit 2, without a shift register
+    pub fn microphone_bit(&self) -> u8 {
+        (self.microphone as u8) << 2
+    }
 }
 
 #[cfg(test)]
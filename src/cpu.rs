@@ -1,10 +1,15 @@
 #![allow(dead_code)]
-use std::collections::HashMap;
 use bitflags::bitflags;
 use instructions;
 use memory::Bus;
 use memory::Mem;
 
+pub mod addressing;
+pub mod ops;
+
+pub use self::addressing::AddressingMode;
+use self::addressing::page_crossed;
+
 bitflags!{
 /*
     7  bit  0
@@ -43,21 +48,12 @@ pub struct Cpu<'a> {
     pub y: u8,
     pub stat: StatFlags,
     pub bus: Bus<'a>,
-}
-
-#[derive(Debug)]
-pub enum AddressingMode {
-    Immediate,
-    ZeroPage,
-    ZeroPageX,
-    ZeroPageY,
-    Absolute,
-    AbsoluteX,
-    AbsoluteY,
-    IndirectX,
-    IndirectY,
-    Relative,
-    Implied,
+    // number of times each opcode was executed, when enabled
+    pub opcode_counts: Option<Vec<u64>>,
+    // set by halt(), run_with_callback returns before the next instruction
+    halted: bool,
+    // stopped by a KIL/JAM opcode, only a reset recovers
+    jammed: bool,
 }
 
 impl Mem for Cpu<'_> {
@@ -86,6 +82,8 @@ mod interrupt {
     #[derive(PartialEq, Eq)]
     pub enum InterruptType {
         NMI,
+        Irq,
+        Brk,
     }
 
     #[derive(PartialEq, Eq)]
@@ -102,6 +100,21 @@ mod interrupt {
         b_flag_mask: 0b00100000,
         cpu_cycles: 2,
     };
+
+    pub(super) const IRQ: Interrupt = Interrupt {
+        ty: InterruptType::Irq,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
+        cpu_cycles: 7,
+    };
+
+    // software interrupt, the cycles are counted by the BRK instruction itself
+    pub(super) const BRK: Interrupt = Interrupt {
+        ty: InterruptType::Brk,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00110000,
+        cpu_cycles: 0,
+    };
 }
 
 impl<'a> Cpu<'a> {
@@ -114,15 +127,24 @@ impl<'a> Cpu<'a> {
             y: 0,
             stat: StatFlags::from_bits_truncate(0b100100),
             bus: bus,
+            opcode_counts: None,
+            halted: false,
+            jammed: false,
         }
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
+    pub fn enable_opcode_counter(&mut self) {
+        self.opcode_counts = Some(vec![0; 256]);
+    }
+
+    pub fn load_and_run(&mut self, addr: u16, program: &[u8]) -> Result<(), String> {
+        self.load(addr, program)?;
         // When inserted a new cartridge
         // CPU receives Reset interrupt
         self.reset();
+        self.pc = addr;
         self.run();
+        Ok(())
     }
 
     pub fn reset(&mut self) {
@@ -131,494 +153,100 @@ impl<'a> Cpu<'a> {
         self.stat = StatFlags::from_bits_truncate(0b100100);
         self.pc = self.mem_read_u16(0xFFFC);
         self.sp = STACK_RESET;
+        self.jammed = false;
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        for i in 0..(program.len() as u16) {
-            self.mem_write(0x8000 + i, program[i as usize]);
+    // copy a program into RAM or PRG RAM, ROM can't be written from here
+    pub fn load(&mut self, addr: u16, program: &[u8]) -> Result<(), String> {
+        for (i, data) in program.iter().enumerate() {
+            self.bus.poke(addr.wrapping_add(i as u16), *data)?;
         }
+        Ok(())
     }
 
     fn interrupt(&mut self, interrupt: interrupt:: Interrupt) {
         self.stack_push_u16(self.pc);
         let mut stat = self.stat.clone();
-        stat.set(StatFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
-        stat.set(StatFlags::BREAK2, interrupt.b_flag_mask & 0b100000 == 1);
+        stat.set(StatFlags::BREAK, interrupt.b_flag_mask & 0b010000 != 0);
+        stat.set(StatFlags::BREAK2, interrupt.b_flag_mask & 0b100000 != 0);
         self.stack_push(stat.bits);
         self.stat.insert(StatFlags::INTERRUPT_DISABLE);
         self.bus.tick(interrupt.cpu_cycles);
         self.pc = self.mem_read_u16(interrupt.vector_addr);
     }
 
-    pub fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
-        match mode {
-            &AddressingMode::Immediate => self.pc,
-            &AddressingMode::ZeroPage => self.mem_read(self.pc) as u16,
-            &AddressingMode::Absolute => self.mem_read_u16(self.pc) as u16,
-            &AddressingMode::ZeroPageX => {
-                let base = self.mem_read(self.pc);
-                let addr = base.wrapping_add(self.x) as u16;
-                addr
-            },
-            &AddressingMode::ZeroPageY => {
-                let base = self.mem_read(self.pc);
-                let addr = base.wrapping_add(self.y) as u16;
-                addr
-            },
-            &AddressingMode::AbsoluteX => {
-                let base = self.mem_read_u16(self.pc);
-                let addr = base.wrapping_add(self.x as u16);
-                addr
-            },
-            &AddressingMode::AbsoluteY => {
-                let base = self.mem_read_u16(self.pc);
-                let addr = base.wrapping_add(self.y as u16);
-                addr
-            },
-            &AddressingMode::IndirectX => {
-                let base = self.mem_read(self.pc);
-                let ptr = base.wrapping_add(self.x);
-                let low = self.mem_read(ptr as u16);
-                let high = self.mem_read(ptr.wrapping_add(1) as u16);
-                (high as u16) << 8 | (low as u16)
-            },
-            &AddressingMode::IndirectY => {
-                let base = self.mem_read(self.pc);
-                let ptr = base.wrapping_add(self.y);
-                let low = self.mem_read(ptr as u16);
-                let high = self.mem_read(ptr.wrapping_add(1) as u16);
-                (high as u16) << 8 | (low as u16)
-            },
-            &AddressingMode::Implied | &AddressingMode::Relative => panic!(),
-        }
+    // the address and whether indexing crossed a page, which costs read instructions a cycle
+    pub fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
+        addressing::operand_address(mode, self.pc, self.x, self.y, |addr| self.mem_read(addr))
     }
 
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
 
+    // stop the run loop, usually called from the callback
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
+    // for tests: stop in front of a BRK instead of taking the interrupt
+    #[cfg(test)]
+    pub fn run_until_brk<F>(&mut self, mut callback: F)
+    where F: FnMut(&mut Cpu) {
+        self.run_with_callback(|cpu| {
+            if cpu.mem_read(cpu.pc) == 0x00 {
+                cpu.halt();
+            } else {
+                callback(cpu);
+            }
+        });
+    }
+
     pub fn run_with_callback<F>(&mut self, mut callback: F) 
     where F: FnMut(&mut Cpu) {
-        let ref instructions: HashMap<u8, &'static instructions::Instruction> = *instructions::INSTRUCTION_MAP;
+        let ref instructions: [Option<&'static instructions::Instruction>; 256] = *instructions::INSTRUCTION_TABLE;
         
         loop {
             // check interruptions
             if let Some(_nmi) = self.bus.poll_nmi_status() {
                 self.interrupt(interrupt::NMI);
+            } else if self.bus.poll_irq_status() && !self.stat.contains(StatFlags::INTERRUPT_DISABLE) {
+                self.interrupt(interrupt::IRQ);
             }
             callback(self);
+            if self.halted {
+                self.halted = false;
+                return;
+            }
 
             let opcode = self.mem_read(self.pc);
             self.pc += 1;
+            if let Some(counts) = self.opcode_counts.as_mut() {
+                counts[opcode as usize] += 1;
+            }
             let pc_to_operand = self.pc;
 
             // debug
             //println!("PC: {:04X} opcode: 0x{:X}", self.pc, opcode);
-            let cur_inst = instructions.get(&opcode).expect(&format!("opcode 0x{:X} is not recognized", opcode));
-
-            match opcode {
-                // BRK
-                // TODO: interruption
-                0x00 => return,
-                // TAX
-                0xAA => {
-                    self.x = self.a;
-                    self.update_zero_and_negative_flags(self.x);
-                },
-                // TXA
-                0x8a => {
-                    self.a = self.x;
-                    self.update_zero_and_negative_flags(self.a);
-                }
-                // TAY
-                0xa8 => {
-                    self.y = self.a;
-                    self.update_zero_and_negative_flags(self.y);
-                },
-                // TYA
-                0x98 => {
-                    self.a = self.y;
-                    self.update_zero_and_negative_flags(self.a);
-                },
-                // TSX
-                0xba => {
-                    self.x = self.sp;
-                    self.update_zero_and_negative_flags(self.x);
-                },
-                // TXS
-                0x9a => {
-                    self.sp = self.x;
-                    self.update_zero_and_negative_flags(self.sp);
-                },
-                // LDA
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&cur_inst.mode);
-                },
-                // LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&cur_inst.mode);
-                },
-                // LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&cur_inst.mode);
-                }
-                // STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&cur_inst.mode);
-                },
-                // STX
-                0x86 | 0x96 | 0x8e => {
-                    self.stx(&cur_inst.mode);
-                },
-                // STY
-                0x84 | 0x94 | 0x8c => {
-                    self.sty(&cur_inst.mode)
-                },
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&cur_inst.mode);
-                },
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&cur_inst.mode);
-                },
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&cur_inst.mode);
-                }
-                // ASL accumulator
-                0x0a => {
-                    self.asl_accumulator();
-                },
-                // ASL
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&cur_inst.mode);
-                },
-                // LSR accumulator
-                0x4a => {
-                    self.lsr_accumulator();
-                },
-                // LSR
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&cur_inst.mode);
-                },
-                // ROL accumulator
-                0x2a => self.rol_accumulator(),
-                // ROL
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&cur_inst.mode);
-                },
-                // ROR accumulator
-                0x6a => self.ror_accumulator(),
-                // ROR
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&cur_inst.mode);
-                },
-                //BIT
-                0x24 | 0x2c => {
-                    self.bit(&cur_inst.mode);
-                },
-                // CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&cur_inst.mode, self.a);
-                },
-                // CPX
-                0xe0 | 0xe4 | 0xec => {
-                    self.compare(&cur_inst.mode, self.x);
-                },
-                // CPY
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&cur_inst.mode, self.y);
-                },
-                // DEC
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&cur_inst.mode);
-                },
-                // DEX
-                0xca => self.dex(),
-                // DEY
-                0x88 => self.dey(),
-                // INC
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&cur_inst.mode);
-                },
-                // INX
-                0xe8 => self.inx(),
-                // INY
-                0xc8 => self.iny(),
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&cur_inst.mode);
-                },
-                // SBC
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                    self.sbc(&cur_inst.mode);
-                },
-                // PHA
-                0x48 => self.stack_push(self.a),
-                // PLA
-                0x68 => self.pla(),
-                // PHP
-                0x08 => self.php(),
-                // PLP
-                0x28 => self.plp(),
-                //RTI
-                0x40 => {
-                    self.stat.bits = self.stack_pop();
-                    self.stat.remove(StatFlags::BREAK);
-                    self.stat.insert(StatFlags::BREAK2);
-                    self.pc = self.stack_pop_u16();
-                }
-                //RTS
-                0x60 => {
-                    self.pc = self.stack_pop_u16() + 1;
-                }
-                // JMP absolute
-                0x4c => {
-                    let addr = self.mem_read_u16(self.pc);
-                    self.pc = addr;
-                },
-                // JMP Indirect
-                0x6c => {
-                    let addr = self.mem_read_u16(self.pc);
-                    let indirect_ref = if addr & 0x00ff == 0x00ff {
-                        let low = self.mem_read(addr);
-                        let high = self.mem_read(addr & 0xFF00);
-                        (high as u16) << 8 | (low as u16)
-                    } else {
-                        self.mem_read_u16(addr)
-                    };
-                    self.pc = indirect_ref;
-                }
-                // JSR absolute
-                0x20 => {
-                    self.stack_push_u16(self.pc + 2 - 1);
-                    let addr = self.mem_read_u16(self.pc);
-                    self.pc = addr;
-                },
-                // BCC
-                0x90 => self.branch(!self.stat.contains(StatFlags::CARRY)),
-                // BCS
-                0xb0 => self.branch(self.stat.contains(StatFlags::CARRY)),
-                // BEQ
-                0xf0 => self.branch(self.stat.contains(StatFlags::ZERO)),
-                // BNE
-                0xd0 => self.branch(!self.stat.contains(StatFlags::ZERO)),
-                // BPL
-                0x10 => self.branch(!self.stat.contains(StatFlags::NEGATIVE)),
-                // BMI
-                0x30 => self.branch(self.stat.contains(StatFlags::NEGATIVE)),
-                // BVC
-                0x50 => self.branch(!self.stat.contains(StatFlags::OVERFLOW)),
-                // BVS
-                0x70 => self.branch(self.stat.contains(StatFlags::OVERFLOW)),
-                // CLC
-                0x18 => self.stat.remove(StatFlags::CARRY),
-                // SEC
-                0x38 => self.stat.insert(StatFlags::CARRY),
-                // CLI
-                0x58 => self.stat.remove(StatFlags::INTERRUPT_DISABLE),
-                // SEI
-                0x78 => self.stat.insert(StatFlags::INTERRUPT_DISABLE),
-                // CLV
-                0xb8 => self.stat.remove(StatFlags::OVERFLOW),
-                // CLD
-                0xd8 => self.stat.remove(StatFlags::DECIMAL),
-                // SED
-                0xf8 => self.stat.insert(StatFlags::DECIMAL),
-                // NOP
-                0xea => (),
-
-                /* Atari 6502 instructions (Unofficial) */
-
-                // DCP
-                0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_sub(1);
-                    self.mem_write(addr, data);
-                    if data <= self.a {
-                        self.set_carry();
-                    }
-                    self.update_zero_and_negative_flags(self.a.wrapping_sub(data));
-                },
-                // RLA
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
-                    let data = self.rol(&cur_inst.mode);
-                    self.and_with_a(data);
-                },
-                // SLO
-                0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
-                    let data = self.asl(&cur_inst.mode);
-                    self.or_with_a(data);
-                },
-                // SRE
-                0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
-                    let data = self.lsr(&cur_inst.mode);
-                    self.xor_with_a(data);
-                },
-                // SKB
-                // TODO: should read memory?
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => (),
-                // AXS
-                0xcb => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    let and = self.x & self.a;
-                    let res = and.wrapping_sub(data);
-                    if data <= and {
-                        self.set_carry();
-                    }
-                    self.update_zero_and_negative_flags(res);
-                    self.x = res;
-                },
-                // ARR
-                0x6b => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_a(data);
-                    self.ror_accumulator();
-                    // TODO: correct?
-                    let res = self.a;
-                    let bit_5 = (res >> 5) & 1;
-                    let bit_6 = (res >> 6) & 1;
-                    if bit_6 == 1 {
-                        self.set_carry();
-                    } else {
-                        self.clear_carry();
-                    }
-                    if bit_5 ^ bit_6 == 1 {
-                        self.set_overflow();
-                    } else {
-                        self.clear_overflow();
-                    }
-                    self.update_zero_and_negative_flags(res)
-                },
-                // SBC
-                0xeb => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    self.sub_from_a(data);
-                },
-                // ANC
-                0x0b | 0x2b => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_a(data);
-                    if self.stat.contains(StatFlags::NEGATIVE) {
-                        self.set_carry();
-                    } else {
-                        self.clear_carry();
-                    }
-                },
-                // ALR
-                0x4b => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    self.add_to_a(data);
-                    self.lsr_accumulator();
-                },
-                // NOP (but do read memory)
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                    | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let _data = self.mem_read(addr);
-                },
-                // RRA
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                    let data = self.ror(&cur_inst.mode);
-                    self.add_to_a(data);
-                },
-                // ISB
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                    let data = self.inc(&cur_inst.mode);
-                    self.sub_from_a(data);
-                },
-                // NOP (do NOTHING)
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => (),
-                // NOP
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => (),
-                // LAX
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    self.a = data;
-                    self.update_zero_and_negative_flags(self.a);
-                    self.x = self.a;
-                },
-                // SAX
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let data = self.a & self.x;
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    self.mem_write(addr, data);
-                },
-                // LXA
-                0xab => {
-                    self.lda(&cur_inst.mode);
-                    self.tax();
-                },
-                // XAA
-                0x8b => {
-                    self.a = self.x;
-                    self.update_zero_and_negative_flags(self.a);
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let data = self.mem_read(addr);
-                    self.and_with_a(data);
-                },
-                /* LAS */
-                0xbb => {
-                    let addr = self.get_operand_address(&cur_inst.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data & self.sp;
-                    self.a = data;
-                    self.x = data;
-                    self.sp = data;
-                    self.update_zero_and_negative_flags(data);
-                },
-                // TAS
-                0x9b => {
-                    let data = self.a & self.x;
-                    self.sp = data;
-                    let mem_address =
-                        self.mem_read_u16(self.pc) + self.y as u16;
-
-                    let data = ((mem_address >> 8) as u8 + 1) & self.sp;
-                    self.mem_write(mem_address, data)
-                }
-
-                // AHX  Indirect Y
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.pc);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.y as u16;
-                    let data = self.a & self.x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                },
-                // AHX Absolute Y
-                0x9f => {
-                    let mem_address = self.mem_read_u16(self.pc) + self.y as u16;
-                    let data = self.a & self.x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                },
-                /* SHX */
-                0x9e => {
-                    let mem_address = self.mem_read_u16(self.pc) + self.y as u16;
-                    // TODO: if cross page boundry {
-                    //     mem_address &= (self.x as u16) << 8;
-                    // }
-                    let data = self.x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                },
-                /* SHY */
-                0x9c => {
-                    let mem_address = self.mem_read_u16(self.pc) + self.x as u16;
-                    let data = self.y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                },
-                //_ => panic!("0x{:X} is not impremented", opcode),
-            }
+            let cur_inst = instructions[opcode as usize]
+                .unwrap_or_else(|| panic!("opcode 0x{:X} is not recognized", opcode));
+            // extra cycles for page crossing and taken branches
+            let extra_cycles = (cur_inst.handler)(self, cur_inst);
 
             // notify PPU about ticks the current instruction took
-            // TODO: support variable cycles isntructions (BNE etc.)
-            self.bus.tick(cur_inst.cycles);
+            self.bus.tick(cur_inst.cycles + extra_cycles);
+            // the clock keeps running while DMA halts the CPU
+            loop {
+                let stall = self.bus.take_dma_stall();
+                if stall == 0 {
+                    break;
+                }
+                self.bus.tick(stall);
+            }
 
             // add up pc unless current instruction is jxx
             if pc_to_operand == self.pc {
@@ -627,64 +255,71 @@ impl<'a> Cpu<'a> {
         }
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn lda(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         self.a = self.mem_read(addr);
         self.update_zero_and_negative_flags(self.a);
+        page_crossed
     }
 
-    fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn ldx(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         self.x = self.mem_read(addr);
         self.update_zero_and_negative_flags(self.x);
+        page_crossed
     }
 
-    fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn ldy(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         self.y = self.mem_read(addr);
         self.update_zero_and_negative_flags(self.y);
+        page_crossed
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         self.mem_write(addr, self.a);
     }
 
     fn stx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         self.mem_write(addr, self.x);
     }
 
     fn sty(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         self.mem_write(addr, self.y);
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn adc(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         let val = self.mem_read(addr);
         self.add_to_a(val);
+        page_crossed
     }
 
-    fn and(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn and(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         let val = self.mem_read(addr);
         self.a = val & self.a;
         self.update_zero_and_negative_flags(self.a);
+        page_crossed
     }
 
-    fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn ora(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         let val = self.mem_read(addr);
         self.a = val | self.a;
         self.update_zero_and_negative_flags(self.a);
+        page_crossed
     }
 
-    fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn eor(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         self.a = data ^ self.a;
         self.update_zero_and_negative_flags(self.a);
+        page_crossed
     }
 
     fn tax(&mut self) {
@@ -694,7 +329,7 @@ impl<'a> Cpu<'a> {
 
     fn asl_accumulator(&mut self) {
         let mut data = self.a;
-        if data >> 1 == 1 {
+        if data >> 7 == 1 {
             self.set_carry();
         } else {
             self.clear_carry();
@@ -705,9 +340,9 @@ impl<'a> Cpu<'a> {
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
-        if data >> 1 == 1 {
+        if data >> 7 == 1 {
             self.set_carry();
         } else {
             self.clear_carry();
@@ -731,7 +366,7 @@ impl<'a> Cpu<'a> {
     }
 
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
         if data & 1 == 1 {
             self.set_carry();
@@ -761,7 +396,7 @@ impl<'a> Cpu<'a> {
     }
 
     fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
         let tmp_carry = self.stat.contains(StatFlags::CARRY);
         if data >> 7 == 1 {
@@ -795,7 +430,7 @@ impl<'a> Cpu<'a> {
     }
 
     fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
         let tmp_carry = self.stat.contains(StatFlags::CARRY);
         if data & 1 == 1 {
@@ -813,7 +448,7 @@ impl<'a> Cpu<'a> {
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         let and = self.a & data;
         if and == 0 {
@@ -825,8 +460,8 @@ impl<'a> Cpu<'a> {
         self.stat.set(StatFlags::OVERFLOW, data & (1<<6) > 0);
     }
 
-    fn compare(&mut self, mode: &AddressingMode, with: u8) {
-        let addr = self.get_operand_address(mode);
+    fn compare(&mut self, mode: &AddressingMode, with: u8) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         if data < with {
             self.set_carry();
@@ -834,10 +469,11 @@ impl<'a> Cpu<'a> {
             self.clear_carry();
         }
         self.update_zero_and_negative_flags(with.wrapping_sub(data));
+        page_crossed
     }
 
     fn dec(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
         data = data.wrapping_sub(1);
         self.mem_write(addr, data);
@@ -855,7 +491,7 @@ impl<'a> Cpu<'a> {
     }
 
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
         data = data.wrapping_add(1);
         self.mem_write(addr, data);
@@ -873,10 +509,11 @@ impl<'a> Cpu<'a> {
         self.update_zero_and_negative_flags(self.y);
     }
 
-    fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn sbc(&mut self, mode: &AddressingMode) -> bool {
+        let (addr, page_crossed) = self.get_operand_address(mode);
         let val = self.mem_read(addr);
         self.add_to_a((val as i8).wrapping_neg().wrapping_sub(1) as u8);
+        page_crossed
     }
 
     // ignore decimal mode
@@ -945,11 +582,15 @@ impl<'a> Cpu<'a> {
         high << 8 | low
     }
 
-    fn branch(&mut self, cond: bool) {
-        if cond {
-            let rel = self.mem_read(self.pc) as i8;
-            self.pc = self.pc.wrapping_add(1).wrapping_add(rel as u16);
+    // returns the extra cycles: 1 if taken, 2 if the target is on another page
+    fn branch(&mut self, cond: bool) -> u8 {
+        if !cond {
+            return 0;
         }
+        let rel = self.mem_read(self.pc) as i8;
+        let next = self.pc.wrapping_add(1);
+        self.pc = next.wrapping_add(rel as u16);
+        if page_crossed(next, self.pc) { 2 } else { 1 }
     }
 
     fn and_with_a(&mut self, data: u8) {
@@ -1013,100 +654,242 @@ impl<'a> Cpu<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use ines::test;
+    use ines::Rom;
+    use ines::test::TestRomBuilder;
     use trace::trace;
     use ppu::Ppu;
+    use apu::Apu;
+    use controller::Joypad;
+    use ppu::PpuDevice;
+    use memory::BusBuilder;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug, PartialEq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    // register accesses in order
+    #[derive(Debug)]
+    struct RecordingPpu {
+        accesses: Rc<RefCell<Vec<Access>>>,
+        cycles: Rc<RefCell<usize>>,
+    }
+
+    impl PpuDevice for RecordingPpu {
+        fn read_register(&mut self, addr: u16) -> u8 {
+            self.accesses.borrow_mut().push(Access::Read(addr));
+            // in vblank
+            0x80
+        }
+
+        fn peek_register(&self, _addr: u16) -> u8 {
+            0x80
+        }
+
+        fn write_register(&mut self, addr: u16, data: u8) {
+            self.accesses.borrow_mut().push(Access::Write(addr, data));
+        }
+
+        fn write_oam_dma(&mut self, _data: &[u8; 256]) {}
+
+        fn tick(&mut self, cycles: u8) -> bool {
+            *self.cycles.borrow_mut() += cycles as usize;
+            false
+        }
+
+        fn poll_nmi(&mut self) -> Option<u8> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_stub_ppu() {
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let cycles = Rc::new(RefCell::new(0));
+        // LDA #$80, STA $2000, STA $3456 (mirror of $2006), LDA $2002, STA $10, BRK
+        let rom = TestRomBuilder::new().prg(&[
+            0xa9, 0x80, 0x8d, 0x00, 0x20, 0x8d, 0x56, 0x34, 0xad, 0x02, 0x20, 0x85, 0x10, 0x00,
+        ]).build();
+        let bus = BusBuilder::new()
+            .rom(rom)
+            .ppu(Box::new(RecordingPpu { accesses: accesses.clone(), cycles: cycles.clone() }))
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        cpu.run_until_brk(|_| {});
+        assert_eq!(*accesses.borrow(), vec![Access::Write(0x2000, 0x80), Access::Write(0x2006, 0x80), Access::Read(0x2002)]);
+        assert_eq!(cpu.mem_read(0x10), 0x80);
+        // 3 PPU cycles per CPU cycle
+        assert_eq!(*cycles.borrow(), cpu.bus.cycles() * 3);
+        assert_eq!(cpu.bus.ppu_position(), (0, 0));
+    }
 
     #[test]
     fn test_0xa9_lda_immidiate_load_data() {
-        let mut rom = test::create_rom();
-        let prg = vec![0xa9, 0x05, 0x00];
-        for i in 0..prg.len() {
-            rom.prg_rom[i] = prg[i];
-        }
-        let bus = Bus::new(rom, |ppu: &Ppu|{}) ;
+        let rom = TestRomBuilder::new().prg(&[0xa9, 0x05, 0x00]).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         let mut cpu = Cpu::new(bus);
         cpu.reset();
-        cpu.pc = 0x8000;
-        cpu.run();
+        cpu.run_until_brk(|_| {});
         assert!(cpu.stat.bits() & 0b0000_0010 == 0b00);
         assert!(cpu.stat.bits() & 0b1000_0000 == 0);
     }
 
     #[test]
     fn test_0xa9_lda_zero_flag() {
-        let mut rom = test::create_rom();
-        let prg = vec![0xa9, 0x00, 0x00];
-        for i in 0..prg.len() {
-            rom.prg_rom[i] = prg[i];
-        }
-        let bus = Bus::new(rom, |ppu: &Ppu| {});
+        let rom = TestRomBuilder::new().prg(&[0xa9, 0x00, 0x00]).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         let mut cpu = Cpu::new(bus);
         cpu.reset();
-        cpu.pc = 0x8000;
-        cpu.run();
+        cpu.run_until_brk(|_| {});
         assert!(cpu.stat.contains(StatFlags::ZERO));
     }
 
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
-        let mut rom = test::create_rom();
-        let prg = vec![0xa9, 0x0a, 0xaa, 0x00];
-        for i in 0..prg.len() {
-            rom.prg_rom[i] = prg[i];
-        }
-        let bus = Bus::new(rom, |ppu: &Ppu| {});
+        let rom = TestRomBuilder::new().prg(&[0xa9, 0x0a, 0xaa, 0x00]).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         let mut cpu = Cpu::new(bus);
         cpu.reset();
-        cpu.pc = 0x8000;
-        cpu.run();
+        cpu.run_until_brk(|_| {});
         assert_eq!(cpu.x, 10)
     }
 
     #[test]
     fn test_5_ops_working_together() {
-        let mut rom = test::create_rom();
-        let prg = vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00];
-        for i in 0..prg.len() {
-            rom.prg_rom[i] = prg[i];
-        }
-        let bus = Bus::new(rom, |ppu: &Ppu| {});
+        let rom = TestRomBuilder::new().prg(&[0xa9, 0xc0, 0xaa, 0xe8, 0x00]).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         let mut cpu = Cpu::new(bus);
         cpu.reset();
-        cpu.pc = 0x8000;
-        cpu.run();
+        cpu.run_until_brk(|_| {});
         assert_eq!(cpu.x, 0xc1)
     }
 
     #[test]
     fn test_inx_overflow() {
-        let mut rom = test::create_rom();
-        let prg = vec![0xa2, 0xff, 0xe8, 0xe8, 0x00];
-        for i in 0..prg.len() {
-            rom.prg_rom[i] = prg[i];
-        }
-        let bus = Bus::new(rom, |ppu: &Ppu| {});
+        let rom = TestRomBuilder::new().prg(&[0xa2, 0xff, 0xe8, 0xe8, 0x00]).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         let mut cpu = Cpu::new(bus);
         cpu.reset();
-        cpu.pc = 0x8000;
-        cpu.run();
+        cpu.run_until_brk(|_| {});
         assert_eq!(cpu.x, 1)
     }
 
+    #[test]
+    fn test_brk() {
+        let mut prg = vec![0; 0x8000];
+        // BRK; padding; LDX #$01; BRK (halt)
+        prg[0..5].copy_from_slice(&[0x00, 0xff, 0xa2, 0x01, 0x00]);
+        // handler at $9000: LDA #$05; RTI
+        prg[0x1000..0x1003].copy_from_slice(&[0xa9, 0x05, 0x40]);
+        prg[0x7ffe] = 0x00;
+        prg[0x7fff] = 0x90;
+        let rom = TestRomBuilder::new().prg(&prg).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        let mut stat = 0;
+        let mut count = 0;
+        cpu.run_with_callback(|cpu| {
+            count += 1;
+            if cpu.pc == 0x9000 {
+                // B flag is set in the pushed status
+                stat = cpu.mem_read(0x0100 + cpu.sp.wrapping_add(1) as u16);
+            }
+            if count == 5 {
+                cpu.halt();
+            }
+        });
+        assert_eq!(stat & 0b0011_0000, 0b0011_0000);
+        assert_eq!(cpu.a, 0x05);
+        assert_eq!(cpu.x, 0x01);
+        assert_eq!(cpu.pc, 0x8004);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut prg = vec![0; 0x8000];
+        // CLI; JMP $8001
+        prg[0..4].copy_from_slice(&[0x58, 0x4c, 0x01, 0x80]);
+        // IRQ handler at $9000: LDA $4015 (acknowledge); BRK
+        prg[0x1000..0x1004].copy_from_slice(&[0xad, 0x15, 0x40, 0x00]);
+        prg[0x7ffe] = 0x00;
+        prg[0x7fff] = 0x90;
+        let rom = TestRomBuilder::new().prg(&prg).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        // the APU frame counter raises the IRQ after about 29830 cycles
+        cpu.run_until_brk(|_| {});
+        assert_eq!(cpu.pc, 0x9003);
+        assert_eq!(cpu.a & 0b0100_0000, 0b0100_0000);
+        assert!(cpu.stat.contains(StatFlags::INTERRUPT_DISABLE));
+        assert!(!cpu.bus.poll_irq_status());
+        // return address and status pushed without the B flag
+        let stat = cpu.mem_read(0x0100 + cpu.sp.wrapping_add(1) as u16);
+        assert_eq!(stat & 0b0011_0000, 0b0010_0000);
+        assert_eq!(cpu.mem_read_u16(0x0100 + cpu.sp.wrapping_add(2) as u16), 0x8001);
+    }
+
     #[test]
     fn test_lda_from_memory() {
-        let mut rom = test::create_rom();
-        let prg = vec![0xa5, 0x10, 0x00];
-        for i in 0..prg.len() {
-            rom.prg_rom[i] = prg[i];
-        }
-        let bus = Bus::new(rom, |ppu: &Ppu| {});
+        let rom = TestRomBuilder::new().prg(&[0xa5, 0x10, 0x00]).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         let mut cpu = Cpu::new(bus);
         cpu.reset();
-        cpu.pc = 0x8000;
         cpu.mem_write(0x10, 0x55);
-        cpu.run();
+        cpu.run_until_brk(|_| {});
         assert_eq!(cpu.a, 0x55);
     }
-}
 
+    #[test]
+    fn test_page_cross_cycles() {
+        // LDX #$01; LDA $10ff,X (crosses into $1100); LDA $1000,X; STA $10ff,X
+        let rom = TestRomBuilder::new()
+            .prg(&[0xa2, 0x01, 0xbd, 0xff, 0x10, 0xbd, 0x00, 0x10, 0x9d, 0xff, 0x10, 0x00])
+            .build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        let start = cpu.bus.cycles();
+        cpu.run_until_brk(|_| {});
+        // stores always take the extra cycle, it's already in their count
+        assert_eq!(cpu.bus.cycles() - start, 2 + 5 + 4 + 5);
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        let mut prg = vec![0; 0x200];
+        // JMP $80f8
+        prg[0..3].copy_from_slice(&[0x4c, 0xf8, 0x80]);
+        // SEC; BCC +0 (not taken); BCS +0 (taken); BCS $810f (taken, to the next page)
+        prg[0xf8..0xff].copy_from_slice(&[0x38, 0x90, 0x00, 0xb0, 0x00, 0xb0, 0x10]);
+        let rom = TestRomBuilder::new().prg(&prg).build();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        let start = cpu.bus.cycles();
+        cpu.run_until_brk(|_| {});
+        assert_eq!(cpu.pc, 0x810f);
+        assert_eq!(cpu.bus.cycles() - start, 3 + 2 + 2 + 3 + 4);
+    }
+
+    #[test]
+    fn test_load_into_ram() {
+        let rom = Rom::from_binary(&[], 0x0600, 0x0600, None).unwrap();
+        let bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        // LDA #$05; STA $6000; BRK
+        cpu.load(0x0600, &[0xa9, 0x05, 0x8d, 0x00, 0x60, 0x00]).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.pc, 0x0600);
+        cpu.run_until_brk(|_| {});
+        assert_eq!(cpu.mem_read(0x6000), 0x05);
+
+        assert!(cpu.load(0x8000, &[0xea]).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+// effective addresses of the 6502 addressing modes
+// https://www.nesdev.org/wiki/CPU_addressing_modes
+
+#[derive(Debug)]
+pub enum AddressingMode {
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    // ($xx,X): X is added to the zero page pointer
+    IndirectX,
+    // ($xx),Y: Y is added to the address the zero page pointer holds
+    IndirectY,
+    // JMP ($xxxx)
+    Indirect,
+    // shifts and rotates of A
+    Accumulator,
+    Relative,
+    Implied,
+}
+
+pub fn page_crossed(a: u16, b: u16) -> bool {
+    a & 0xff00 != b & 0xff00
+}
+
+fn read_u16<F>(addr: u16, read: &mut F) -> u16
+where F: FnMut(u16) -> u8 {
+    let low = read(addr);
+    let high = read(addr.wrapping_add(1));
+    (high as u16) << 8 | (low as u16)
+}
+
+// little endian pointer in the zero page, the high byte wraps to $00
+fn zero_page_pointer<F>(ptr: u8, read: &mut F) -> u16
+where F: FnMut(u16) -> u8 {
+    let low = read(ptr as u16);
+    let high = read(ptr.wrapping_add(1) as u16);
+    (high as u16) << 8 | (low as u16)
+}
+
+// the address the operand at pc refers to, and whether indexing crossed a page
+// (reads take an extra cycle then)
+pub fn operand_address<F>(mode: &AddressingMode, pc: u16, x: u8, y: u8, mut read: F) -> (u16, bool)
+where F: FnMut(u16) -> u8 {
+    match mode {
+        &AddressingMode::Immediate => (pc, false),
+        &AddressingMode::ZeroPage => (read(pc) as u16, false),
+        &AddressingMode::Absolute => (read_u16(pc, &mut read), false),
+        &AddressingMode::ZeroPageX => (read(pc).wrapping_add(x) as u16, false),
+        &AddressingMode::ZeroPageY => (read(pc).wrapping_add(y) as u16, false),
+        &AddressingMode::AbsoluteX => {
+            let base = read_u16(pc, &mut read);
+            let addr = base.wrapping_add(x as u16);
+            (addr, page_crossed(base, addr))
+        },
+        &AddressingMode::AbsoluteY => {
+            let base = read_u16(pc, &mut read);
+            let addr = base.wrapping_add(y as u16);
+            (addr, page_crossed(base, addr))
+        },
+        &AddressingMode::IndirectX => {
+            let ptr = read(pc).wrapping_add(x);
+            (zero_page_pointer(ptr, &mut read), false)
+        },
+        &AddressingMode::IndirectY => {
+            let ptr = read(pc);
+            let base = zero_page_pointer(ptr, &mut read);
+            let addr = base.wrapping_add(y as u16);
+            (addr, page_crossed(base, addr))
+        },
+        &AddressingMode::Indirect => {
+            let ptr = read_u16(pc, &mut read);
+            // the pointer's high byte doesn't carry into the next page
+            let low = read(ptr);
+            let high = read(ptr & 0xff00 | (ptr as u8).wrapping_add(1) as u16);
+            ((high as u16) << 8 | (low as u16), false)
+        },
+        &AddressingMode::Accumulator | &AddressingMode::Implied | &AddressingMode::Relative => {
+            panic!("addressing mode {:?} has no operand address", mode)
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // operand at $0600
+    fn resolve(mode: AddressingMode, memory: &[(u16, u8)], x: u8, y: u8) -> (u16, bool) {
+        let mut ram = vec![0u8; 0x10000];
+        for &(addr, data) in memory {
+            ram[addr as usize] = data;
+        }
+        operand_address(&mode, 0x600, x, y, |addr| ram[addr as usize])
+    }
+
+    #[test]
+    fn test_indexed() {
+        assert_eq!(resolve(AddressingMode::ZeroPageX, &[(0x600, 0xf0)], 0x20, 0), (0x10, false));
+        assert_eq!(resolve(AddressingMode::ZeroPageY, &[(0x600, 0x10)], 0, 0x20), (0x30, false));
+        let operand = [(0x600, 0xf0), (0x601, 0x12)];
+        assert_eq!(resolve(AddressingMode::AbsoluteX, &operand, 0x0f, 0), (0x12ff, false));
+        assert_eq!(resolve(AddressingMode::AbsoluteX, &operand, 0x10, 0), (0x1300, true));
+        assert_eq!(resolve(AddressingMode::AbsoluteY, &operand, 0, 0x20), (0x1310, true));
+        // wraps around the address space
+        let operand = [(0x600, 0xff), (0x601, 0xff)];
+        assert_eq!(resolve(AddressingMode::AbsoluteY, &operand, 0, 1), (0x0000, true));
+    }
+
+    #[test]
+    fn test_indirect_x() {
+        let memory = [(0x600, 0x20), (0x24, 0x74), (0x25, 0x20)];
+        assert_eq!(resolve(AddressingMode::IndirectX, &memory, 4, 0), (0x2074, false));
+        // the pointer stays in the zero page
+        let memory = [(0x600, 0xfe), (0xff, 0x34), (0x00, 0x12), (0x100, 0x56)];
+        assert_eq!(resolve(AddressingMode::IndirectX, &memory, 1, 0), (0x1234, false));
+    }
+
+    #[test]
+    fn test_indirect_y() {
+        // Y is added to the fetched address, not to the pointer
+        let memory = [(0x600, 0x86), (0x86, 0x28), (0x87, 0x40), (0x88, 0x99)];
+        assert_eq!(resolve(AddressingMode::IndirectY, &memory, 0, 0x10), (0x4038, false));
+        assert_eq!(resolve(AddressingMode::IndirectY, &memory, 0, 0xd8), (0x4100, true));
+        // the high byte of the pointer comes from $00, not $100
+        let memory = [(0x600, 0xff), (0xff, 0xf0), (0x00, 0x03), (0x100, 0x56)];
+        assert_eq!(resolve(AddressingMode::IndirectY, &memory, 0, 0x20), (0x0410, true));
+        assert_eq!(resolve(AddressingMode::IndirectY, &[(0x600, 0x10), (0x10, 0xff), (0x11, 0xff)], 0, 1), (0x0000, true));
+    }
+
+    #[test]
+    fn test_indirect() {
+        let memory = [(0x600, 0x20), (0x601, 0x01), (0x120, 0xfc), (0x121, 0xba)];
+        assert_eq!(resolve(AddressingMode::Indirect, &memory, 0, 0), (0xbafc, false));
+        // the pointer wraps within its page
+        let memory = [(0x600, 0xff), (0x601, 0x02), (0x2ff, 0x00), (0x200, 0x04), (0x300, 0x80)];
+        assert_eq!(resolve(AddressingMode::Indirect, &memory, 0, 0), (0x0400, false));
+    }
+}
@@ -0,0 +1,740 @@
+// what each instruction does, looked up by mnemonic when the instruction table is built
+// a handler runs with pc on the operand and returns the cycles taken on top of
+// Instruction::cycles (page crossing, taken branches)
+
+use cpu::{interrupt, AddressingMode, Cpu, StatFlags};
+use instructions::Instruction;
+use memory::Mem;
+
+pub type Handler = fn(&mut Cpu, &Instruction) -> u8;
+
+// unofficial opcodes share the handler of their mnemonic without the '*'
+pub fn handler(mnemonic: &str) -> Handler {
+    match mnemonic.trim_start_matches('*') {
+        "BRK" => brk,
+        "TAX" => tax,
+        "TXA" => txa,
+        "TAY" => tay,
+        "TYA" => tya,
+        "TSX" => tsx,
+        "TXS" => txs,
+        "LDA" => lda,
+        "LDX" => ldx,
+        "LDY" => ldy,
+        "STA" => sta,
+        "STX" => stx,
+        "STY" => sty,
+        "ADC" => adc,
+        "AND" => and,
+        "ORA" => ora,
+        "EOR" => eor,
+        "SBC" => sbc,
+        "ASL" => asl,
+        "LSR" => lsr,
+        "ROL" => rol,
+        "ROR" => ror,
+        "BIT" => bit,
+        "CMP" => cmp,
+        "CPX" => cpx,
+        "CPY" => cpy,
+        "DEC" => dec,
+        "DEX" => dex,
+        "DEY" => dey,
+        "INC" => inc,
+        "INX" => inx,
+        "INY" => iny,
+        "PHA" => pha,
+        "PLA" => pla,
+        "PHP" => php,
+        "PLP" => plp,
+        "RTI" => rti,
+        "RTS" => rts,
+        "JMP" => jmp,
+        "JSR" => jsr,
+        "BCC" => bcc,
+        "BCS" => bcs,
+        "BEQ" => beq,
+        "BNE" => bne,
+        "BPL" => bpl,
+        "BMI" => bmi,
+        "BVC" => bvc,
+        "BVS" => bvs,
+        "CLC" => clc,
+        "SEC" => sec,
+        "CLI" => cli,
+        "SEI" => sei,
+        "CLV" => clv,
+        "CLD" => cld,
+        "SED" => sed,
+        "NOP" => nop,
+        "JAM" => jam,
+        "DCP" => dcp,
+        "RLA" => rla,
+        "SLO" => slo,
+        "SRE" => sre,
+        "RRA" => rra,
+        "ISB" => isb,
+        "AXS" => axs,
+        "ARR" => arr,
+        "ANC" => anc,
+        "ALR" => alr,
+        "LAX" => lax,
+        "SAX" => sax,
+        "LXA" => lxa,
+        "XAA" => xaa,
+        "LAS" => las,
+        "TAS" => tas,
+        "AHX" => ahx,
+        "SHX" => shx,
+        "SHY" => shy,
+        _ => panic!("no handler for {}", mnemonic),
+    }
+}
+
+fn brk(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    // the byte after BRK is padding, the return address skips it
+    cpu.pc += 1;
+    cpu.interrupt(interrupt::BRK);
+    0
+}
+
+fn tax(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.tax();
+    0
+}
+
+fn txa(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.a = cpu.x;
+    cpu.update_zero_and_negative_flags(cpu.a);
+    0
+}
+
+fn tay(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.y = cpu.a;
+    cpu.update_zero_and_negative_flags(cpu.y);
+    0
+}
+
+fn tya(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.a = cpu.y;
+    cpu.update_zero_and_negative_flags(cpu.a);
+    0
+}
+
+fn tsx(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.x = cpu.sp;
+    cpu.update_zero_and_negative_flags(cpu.x);
+    0
+}
+
+fn txs(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.sp = cpu.x;
+    0
+}
+
+fn lda(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.lda(&inst.mode) as u8
+}
+
+fn ldx(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.ldx(&inst.mode) as u8
+}
+
+fn ldy(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.ldy(&inst.mode) as u8
+}
+
+fn sta(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.sta(&inst.mode);
+    0
+}
+
+fn stx(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.stx(&inst.mode);
+    0
+}
+
+fn sty(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.sty(&inst.mode);
+    0
+}
+
+fn adc(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.adc(&inst.mode) as u8
+}
+
+fn and(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.and(&inst.mode) as u8
+}
+
+fn ora(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.ora(&inst.mode) as u8
+}
+
+fn eor(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.eor(&inst.mode) as u8
+}
+
+fn sbc(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.sbc(&inst.mode) as u8
+}
+
+fn asl(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    match inst.mode {
+        AddressingMode::Accumulator => cpu.asl_accumulator(),
+        _ => {
+            cpu.asl(&inst.mode);
+        },
+    }
+    0
+}
+
+fn lsr(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    match inst.mode {
+        AddressingMode::Accumulator => cpu.lsr_accumulator(),
+        _ => {
+            cpu.lsr(&inst.mode);
+        },
+    }
+    0
+}
+
+fn rol(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    match inst.mode {
+        AddressingMode::Accumulator => cpu.rol_accumulator(),
+        _ => {
+            cpu.rol(&inst.mode);
+        },
+    }
+    0
+}
+
+fn ror(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    match inst.mode {
+        AddressingMode::Accumulator => cpu.ror_accumulator(),
+        _ => {
+            cpu.ror(&inst.mode);
+        },
+    }
+    0
+}
+
+fn bit(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.bit(&inst.mode);
+    0
+}
+
+fn cmp(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let a = cpu.a;
+    cpu.compare(&inst.mode, a) as u8
+}
+
+fn cpx(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let x = cpu.x;
+    cpu.compare(&inst.mode, x);
+    0
+}
+
+fn cpy(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let y = cpu.y;
+    cpu.compare(&inst.mode, y);
+    0
+}
+
+fn dec(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.dec(&inst.mode);
+    0
+}
+
+fn dex(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.dex();
+    0
+}
+
+fn dey(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.dey();
+    0
+}
+
+fn inc(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.inc(&inst.mode);
+    0
+}
+
+fn inx(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.inx();
+    0
+}
+
+fn iny(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.iny();
+    0
+}
+
+fn pha(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let a = cpu.a;
+    cpu.stack_push(a);
+    0
+}
+
+fn pla(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.pla();
+    0
+}
+
+fn php(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.php();
+    0
+}
+
+fn plp(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.plp();
+    0
+}
+
+fn rti(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.bits = cpu.stack_pop();
+    cpu.stat.remove(StatFlags::BREAK);
+    cpu.stat.insert(StatFlags::BREAK2);
+    cpu.pc = cpu.stack_pop_u16();
+    0
+}
+
+fn rts(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.pc = cpu.stack_pop_u16() + 1;
+    0
+}
+
+fn jmp(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    cpu.pc = addr;
+    0
+}
+
+fn jsr(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let ret = cpu.pc + 2 - 1;
+    cpu.stack_push_u16(ret);
+    cpu.pc = cpu.mem_read_u16(cpu.pc);
+    0
+}
+
+fn bcc(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = !cpu.stat.contains(StatFlags::CARRY);
+    cpu.branch(cond)
+}
+
+fn bcs(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = cpu.stat.contains(StatFlags::CARRY);
+    cpu.branch(cond)
+}
+
+fn beq(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = cpu.stat.contains(StatFlags::ZERO);
+    cpu.branch(cond)
+}
+
+fn bne(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = !cpu.stat.contains(StatFlags::ZERO);
+    cpu.branch(cond)
+}
+
+fn bpl(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = !cpu.stat.contains(StatFlags::NEGATIVE);
+    cpu.branch(cond)
+}
+
+fn bmi(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = cpu.stat.contains(StatFlags::NEGATIVE);
+    cpu.branch(cond)
+}
+
+fn bvc(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = !cpu.stat.contains(StatFlags::OVERFLOW);
+    cpu.branch(cond)
+}
+
+fn bvs(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    let cond = cpu.stat.contains(StatFlags::OVERFLOW);
+    cpu.branch(cond)
+}
+
+fn clc(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.remove(StatFlags::CARRY);
+    0
+}
+
+fn sec(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.insert(StatFlags::CARRY);
+    0
+}
+
+fn cli(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.remove(StatFlags::INTERRUPT_DISABLE);
+    0
+}
+
+fn sei(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.insert(StatFlags::INTERRUPT_DISABLE);
+    0
+}
+
+fn clv(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.remove(StatFlags::OVERFLOW);
+    0
+}
+
+fn cld(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.remove(StatFlags::DECIMAL);
+    0
+}
+
+fn sed(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.stat.insert(StatFlags::DECIMAL);
+    0
+}
+
+// the unofficial NOPs with a memory operand do read it
+fn nop(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    match inst.mode {
+        // TODO: should immediate read memory?
+        AddressingMode::Implied | AddressingMode::Immediate => 0,
+        _ => {
+            let (addr, page_crossed) = cpu.get_operand_address(&inst.mode);
+            let _data = cpu.mem_read(addr);
+            page_crossed as u8
+        },
+    }
+}
+
+/* Atari 6502 instructions (Unofficial) */
+
+// KIL/JAM locks up the CPU until reset, run_with_callback returns
+fn jam(cpu: &mut Cpu, _inst: &Instruction) -> u8 {
+    cpu.pc = cpu.pc.wrapping_sub(1);
+    cpu.jammed = true;
+    cpu.halt();
+    0
+}
+
+fn dcp(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    let mut data = cpu.mem_read(addr);
+    data = data.wrapping_sub(1);
+    cpu.mem_write(addr, data);
+    if data <= cpu.a {
+        cpu.set_carry();
+    }
+    let res = cpu.a.wrapping_sub(data);
+    cpu.update_zero_and_negative_flags(res);
+    0
+}
+
+fn rla(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.rol(&inst.mode);
+    cpu.and_with_a(data);
+    0
+}
+
+fn slo(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.asl(&inst.mode);
+    cpu.or_with_a(data);
+    0
+}
+
+fn sre(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.lsr(&inst.mode);
+    cpu.xor_with_a(data);
+    0
+}
+
+fn rra(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.ror(&inst.mode);
+    cpu.add_to_a(data);
+    0
+}
+
+fn isb(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.inc(&inst.mode);
+    cpu.sub_from_a(data);
+    0
+}
+
+fn axs(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.mem_read(addr);
+    let and = cpu.x & cpu.a;
+    let res = and.wrapping_sub(data);
+    if data <= and {
+        cpu.set_carry();
+    }
+    cpu.update_zero_and_negative_flags(res);
+    cpu.x = res;
+    0
+}
+
+fn arr(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.mem_read(addr);
+    cpu.and_with_a(data);
+    cpu.ror_accumulator();
+    // TODO: correct?
+    let res = cpu.a;
+    let bit_5 = (res >> 5) & 1;
+    let bit_6 = (res >> 6) & 1;
+    if bit_6 == 1 {
+        cpu.set_carry();
+    } else {
+        cpu.clear_carry();
+    }
+    if bit_5 ^ bit_6 == 1 {
+        cpu.set_overflow();
+    } else {
+        cpu.clear_overflow();
+    }
+    cpu.update_zero_and_negative_flags(res);
+    0
+}
+
+fn anc(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.mem_read(addr);
+    cpu.and_with_a(data);
+    if cpu.stat.contains(StatFlags::NEGATIVE) {
+        cpu.set_carry();
+    } else {
+        cpu.clear_carry();
+    }
+    0
+}
+
+fn alr(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.mem_read(addr);
+    cpu.and_with_a(data);
+    cpu.lsr_accumulator();
+    0
+}
+
+fn lax(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, page_crossed) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.mem_read(addr);
+    cpu.a = data;
+    cpu.update_zero_and_negative_flags(cpu.a);
+    cpu.x = cpu.a;
+    page_crossed as u8
+}
+
+fn sax(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.a & cpu.x;
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    cpu.mem_write(addr, data);
+    0
+}
+
+fn lxa(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.lda(&inst.mode);
+    cpu.tax();
+    0
+}
+
+fn xaa(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    cpu.a = cpu.x;
+    cpu.update_zero_and_negative_flags(cpu.a);
+    let (addr, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.mem_read(addr);
+    cpu.and_with_a(data);
+    0
+}
+
+fn las(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (addr, page_crossed) = cpu.get_operand_address(&inst.mode);
+    let mut data = cpu.mem_read(addr);
+    data = data & cpu.sp;
+    cpu.a = data;
+    cpu.x = data;
+    cpu.sp = data;
+    cpu.update_zero_and_negative_flags(data);
+    page_crossed as u8
+}
+
+fn tas(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let data = cpu.a & cpu.x;
+    cpu.sp = data;
+    let (mem_address, _) = cpu.get_operand_address(&inst.mode);
+
+    let data = ((mem_address >> 8) as u8).wrapping_add(1) & cpu.sp;
+    cpu.mem_write(mem_address, data);
+    0
+}
+
+fn ahx(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (mem_address, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.a & cpu.x & ((mem_address >> 8) as u8).wrapping_add(1);
+    cpu.mem_write(mem_address, data);
+    0
+}
+
+fn shx(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (mem_address, _) = cpu.get_operand_address(&inst.mode);
+    // TODO: if cross page boundry {
+    //     mem_address &= (cpu.x as u16) << 8;
+    // }
+    let data = cpu.x & ((mem_address >> 8) as u8).wrapping_add(1);
+    cpu.mem_write(mem_address, data);
+    0
+}
+
+fn shy(cpu: &mut Cpu, inst: &Instruction) -> u8 {
+    let (mem_address, _) = cpu.get_operand_address(&inst.mode);
+    let data = cpu.y & ((mem_address >> 8) as u8).wrapping_add(1);
+    cpu.mem_write(mem_address, data);
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::Bus;
+    use ppu::Ppu;
+    use apu::Apu;
+    use controller::Joypad;
+    use ines::test;
+    use instructions::INSTRUCTION_TABLE;
+
+    fn cpu_at(program: &[u8]) -> Cpu<'static> {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        for (i, &byte) in program.iter().enumerate() {
+            bus.mem_write(0x0200 + i as u16, byte);
+        }
+        let mut cpu = Cpu::new(bus);
+        // on the operand, as in the run loop
+        cpu.pc = 0x0201;
+        cpu
+    }
+
+    fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
+        let inst = INSTRUCTION_TABLE[opcode as usize].unwrap();
+        (inst.handler)(cpu, inst)
+    }
+
+    #[test]
+    fn test_lda_page_cross() {
+        // LDA $02ff,X
+        let mut cpu = cpu_at(&[0xbd, 0xff, 0x02]);
+        cpu.mem_write(0x0300, 0x80);
+        cpu.x = 1;
+        assert_eq!(execute(&mut cpu, 0xbd), 1);
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.stat.contains(StatFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_accumulator_shift() {
+        // ASL A
+        let mut cpu = cpu_at(&[0x0a]);
+        cpu.a = 0x81;
+        assert_eq!(execute(&mut cpu, 0x0a), 0);
+        assert_eq!(cpu.a, 0x02);
+        assert!(cpu.stat.contains(StatFlags::CARRY));
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        // JMP ($02ff) reads the high byte from $0200, not $0300
+        let mut cpu = cpu_at(&[0x6c, 0xff, 0x02]);
+        cpu.mem_write(0x02ff, 0x34);
+        cpu.mem_write(0x0300, 0x56);
+        execute(&mut cpu, 0x6c);
+        assert_eq!(cpu.pc, 0x6c34);
+    }
+
+    #[test]
+    fn test_txs_keeps_flags() {
+        // TXS
+        let mut cpu = cpu_at(&[0x9a]);
+        cpu.x = 0;
+        cpu.stat.insert(StatFlags::NEGATIVE);
+        execute(&mut cpu, 0x9a);
+        assert_eq!(cpu.sp, 0);
+        assert!(cpu.stat.contains(StatFlags::NEGATIVE));
+        assert!(!cpu.stat.contains(StatFlags::ZERO));
+    }
+
+    #[test]
+    fn test_alr() {
+        // *ALR #$0f
+        let mut cpu = cpu_at(&[0x4b, 0x0f]);
+        cpu.a = 0x83;
+        execute(&mut cpu, 0x4b);
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.stat.contains(StatFlags::CARRY));
+    }
+
+    #[test]
+    fn test_unofficial_shares_handler() {
+        // *LAX $10
+        let mut cpu = cpu_at(&[0xa7, 0x10]);
+        cpu.mem_write(0x10, 0x42);
+        execute(&mut cpu, 0xa7);
+        assert_eq!((cpu.a, cpu.x), (0x42, 0x42));
+    }
+
+    #[test]
+    fn test_jam() {
+        // *JAM; LDA #$01
+        let mut cpu = cpu_at(&[0x02, 0xa9, 0x01]);
+        cpu.pc = 0x0200;
+        cpu.run();
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.pc, 0x0200);
+        // stays stuck
+        cpu.run();
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.a, 0);
+    }
+
+    #[test]
+    fn test_store_high_byte_wraps() {
+        // *SHX $ff00,Y; *SHY $ff00,X; *TAS $ff00,Y; *AHX $ff00,Y store to $ffff, H + 1 wraps to 0
+        for &opcode in [0x9e, 0x9c, 0x9b, 0x9f].iter() {
+            // AxROM, which takes writes to PRG ROM as bank switches
+            // the first byte of each 32KB bank holds its number
+            let mut prg = vec![0; 4 * 0x8000];
+            for bank in 0..4 {
+                prg[bank * 0x8000] = bank as u8;
+            }
+            let rom = test::TestRomBuilder::new().mapper(7).prg_banks(8).prg(&prg).build();
+            let mut bus = Bus::new(rom, |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+            bus.mem_write(0x0200, opcode);
+            bus.mem_write(0x0201, 0x00);
+            bus.mem_write(0x0202, 0xff);
+            let mut cpu = Cpu::new(bus);
+            cpu.pc = 0x0201;
+            cpu.a = 0xf3;
+            cpu.x = 0xff;
+            cpu.y = 0xff;
+            cpu.mem_write(0x8000, 3);
+            assert_eq!(cpu.mem_read(0x8000), 3);
+            execute(&mut cpu, opcode);
+            // the 0 went to PRG ROM and selected bank 0, an unmasked $ff or $f3 would keep bank 3
+            assert_eq!(cpu.mem_read(0x8000), 0, "opcode {:02x}", opcode);
+            assert_eq!(cpu.mem_read(0x00ff), 0, "opcode {:02x}", opcode);
+            if opcode == 0x9b {
+                assert_eq!(cpu.sp, 0xf3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tas_sets_stack_pointer() {
+        // *TAS $0200,Y
+        let mut cpu = cpu_at(&[0x9b, 0x00, 0x02]);
+        cpu.a = 0xf3;
+        cpu.x = 0x3f;
+        cpu.y = 0x10;
+        execute(&mut cpu, 0x9b);
+        assert_eq!(cpu.sp, 0x33);
+        // H + 1 = $03
+        assert_eq!(cpu.mem_read(0x0210), 0x03);
+    }
+}
@@ -0,0 +1,54 @@
+// counts how often a game hits paths the emulator doesn't implement (or only
+// approximates), so a bug report can say which missing feature a game needs
+// e.g. "unmapped write: 812" or "$2006 write during rendering: 3"
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Result, Write};
+
+thread_local! {
+    static HITS: RefCell<BTreeMap<&'static str, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn hit(what: &'static str) {
+    HITS.with(|hits| *hits.borrow_mut().entry(what).or_insert(0) += 1);
+}
+
+// (what, count) sorted by name
+pub fn summary() -> Vec<(&'static str, u64)> {
+    HITS.with(|hits| hits.borrow().iter().map(|(&what, &count)| (what, count)).collect())
+}
+
+pub fn write_summary<W: Write>(out: &mut W) -> Result<()> {
+    let hits = summary();
+    if hits.is_empty() {
+        return writeln!(out, "no unimplemented features were hit");
+    }
+    writeln!(out, "unimplemented/approximated features hit this session:")?;
+    for (what, count) in hits {
+        writeln!(out, "  {}: {}", what, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut out = vec![];
+        write_summary(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "no unimplemented features were hit\n");
+
+        hit("unmapped write");
+        hit("OAM DMA");
+        hit("unmapped write");
+        assert_eq!(summary(), vec![("OAM DMA", 1), ("unmapped write", 2)]);
+
+        let mut out = vec![];
+        write_summary(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "unimplemented/approximated features hit this session:\n  OAM DMA: 1\n  unmapped write: 2\n");
+    }
+}
@@ -0,0 +1,139 @@
+// random joypad input for crash hunting
+// the same seed always produces the same input sequence
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use controller::JoypadButton;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::rc::Rc;
+use ines;
+use cartridge;
+use memory;
+use cpu;
+use ppu;
+use apu;
+use controller;
+use state;
+
+pub struct InputFuzzer {
+    rng: StdRng,
+}
+
+impl InputFuzzer {
+    pub fn new(seed: u64) -> Self {
+        InputFuzzer {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // buttons held for the next frame
+    // opposite directions are never pressed together, no real pad can do that
+    pub fn next_buttons(&mut self) -> JoypadButton {
+        let mut buttons = JoypadButton::from_bits_truncate(self.rng.gen::<u8>());
+        if buttons.contains(JoypadButton::UP | JoypadButton::DOWN) {
+            buttons.remove(if self.rng.gen::<bool>() { JoypadButton::UP } else { JoypadButton::DOWN });
+        }
+        if buttons.contains(JoypadButton::LEFT | JoypadButton::RIGHT) {
+            buttons.remove(if self.rng.gen::<bool>() { JoypadButton::LEFT } else { JoypadButton::RIGHT });
+        }
+        buttons
+    }
+}
+
+// nes-emu fuzz <file path> [seed] [frames]
+// the state written on a crash is for reading, only the input log can be replayed
+pub fn run(args: &[String]) -> ! {
+    let usage = "usage: nes-emu fuzz <file path> [seed] [frames] (replay a crash with the inputs in fuzz-<seed>.log)";
+    let path = match args.get(2) {
+        Some(path) => path,
+        None => {
+            println!("{}", usage);
+            std::process::exit(0);
+        }
+    };
+    let (seed, frames): (u64, u64) = match (args.get(3).map(|n| n.parse()), args.get(4).map(|n| n.parse())) {
+        (None, None) => (0, 60 * 60 * 10),
+        (Some(Ok(seed)), None) => (seed, 60 * 60 * 10),
+        (Some(Ok(seed)), Some(Ok(frames))) => (seed, frames),
+        _ => {
+            println!("{}", usage);
+            std::process::exit(1);
+        }
+    };
+    let rom = match std::fs::read(path).map_err(|e| e.to_string()).and_then(|raw| ines::Rom::analyze_raw(&raw).map_err(String::from)) {
+        Ok(rom) => rom,
+        Err(msg) => {
+            println!("{}: {}", path, msg);
+            std::process::exit(1);
+        }
+    };
+    if !cartridge::is_supported(rom.mapper) {
+        println!("mapper {} is not supported", rom.mapper);
+        std::process::exit(1);
+    }
+
+    // one line per frame: frame number and the buttons held on joypad 1
+    let log_path = format!("fuzz-{}.log", seed);
+    let mut input_log = match File::create(&log_path) {
+        Ok(file) => LineWriter::new(file),
+        Err(e) => {
+            println!("failed to create {}: {}", log_path, e);
+            std::process::exit(1);
+        }
+    };
+    let mut fuzzer = InputFuzzer::new(seed);
+    let frame = Rc::new(Cell::new(0u64));
+    let current_frame = frame.clone();
+    let bus = memory::Bus::new(rom, move |_ppu: &ppu::Ppu, _apu: &mut apu::Apu, joypads: &mut [controller::Joypad; 2]| {
+        let buttons = fuzzer.next_buttons();
+        joypads[0].set_button_status(controller::JoypadButton::all(), false);
+        joypads[0].set_button_status(buttons, true);
+        current_frame.set(current_frame.get() + 1);
+        writeln!(input_log, "{},{:08b}", current_frame.get(), buttons.bits()).unwrap();
+    });
+    let mut cpu = cpu::Cpu::new(bus);
+    cpu.reset();
+    // panics unwind out of the run loop and are reported below
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        cpu.run_with_callback(move |cpu| {
+            if cpu.bus.frame_count() as u64 >= frames {
+                cpu.halt();
+            }
+        });
+    }));
+    let found = match result {
+        Err(_) => "crashed",
+        Ok(()) if cpu.is_jammed() => "jammed",
+        Ok(()) => {
+            println!("no crash in {} frames", frames);
+            std::process::exit(0);
+        },
+    };
+    // the machine as it was when the run stopped, next to the inputs that got it there
+    let state_path = format!("fuzz-{}.state", seed);
+    if let Err(e) = File::create(&state_path).and_then(|mut file| state::write_state(&cpu, &mut file)) {
+        println!("failed to write {}: {}", state_path, e);
+    }
+    println!("{} at frame {}, PC ${:04X} (seed {}, inputs in {}, state in {})",
+        found, frame.get(), cpu.pc, seed, log_path, state_path);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reproducible() {
+        let mut a = InputFuzzer::new(42);
+        let mut b = InputFuzzer::new(42);
+        for _ in 0..100 {
+            let buttons = a.next_buttons();
+            assert_eq!(buttons, b.next_buttons());
+            assert!(!buttons.contains(JoypadButton::UP | JoypadButton::DOWN));
+            assert!(!buttons.contains(JoypadButton::LEFT | JoypadButton::RIGHT));
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::io::Write;
+
+// per-address read/write counters for a memory region
+#[derive(Debug)]
+pub struct AccessCounter {
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+}
+
+impl AccessCounter {
+    pub fn new(size: usize) -> Self {
+        AccessCounter {
+            reads: vec![0; size],
+            writes: vec![0; size],
+        }
+    }
+
+    pub fn read(&mut self, idx: usize) {
+        self.reads[idx] = self.reads[idx].saturating_add(1);
+    }
+
+    pub fn write(&mut self, idx: usize) {
+        self.writes[idx] = self.writes[idx].saturating_add(1);
+    }
+}
+
+const CELLS_PER_ROW: usize = 64;
+const CELL_SIZE: usize = 4;
+
+// log scale so that a few hot variables don't wash out everything else
+fn intensity(count: u32, max: u32) -> u8 {
+    if count == 0 {
+        return 0;
+    }
+    let scale = ((count as f64) + 1.0).ln() / ((max as f64) + 1.0).ln();
+    (scale * 255.0) as u8
+}
+
+// draw regions top to bottom, one cell per address
+// red: writes, green: reads
+pub fn write_ppm<W: Write>(out: &mut W, regions: &[&AccessCounter]) -> io::Result<()> {
+    let width = CELLS_PER_ROW * CELL_SIZE;
+    let rows = |region: &AccessCounter| region.reads.len().div_ceil(CELLS_PER_ROW);
+    let height = regions.iter().map(|r| rows(r)).sum::<usize>() * CELL_SIZE;
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+
+    for region in regions {
+        let max_read = region.reads.iter().cloned().max().unwrap_or(0);
+        let max_write = region.writes.iter().cloned().max().unwrap_or(0);
+        for row in 0..rows(region) {
+            let mut line = Vec::with_capacity(width * 3);
+            for col in 0..CELLS_PER_ROW {
+                let addr = row * CELLS_PER_ROW + col;
+                let (r, g) = if addr < region.reads.len() {
+                    (intensity(region.writes[addr], max_write), intensity(region.reads[addr], max_read))
+                } else {
+                    (0, 0)
+                };
+                for _ in 0..CELL_SIZE {
+                    line.extend_from_slice(&[r, g, 0]);
+                }
+            }
+            for _ in 0..CELL_SIZE {
+                out.write_all(&line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_ppm() {
+        let mut ram = AccessCounter::new(0x800);
+        ram.read(0);
+        ram.write(0);
+        ram.write(0);
+        ram.write(1);
+        let vram = AccessCounter::new(0x800);
+
+        let mut out = vec![];
+        write_ppm(&mut out, &[&ram, &vram]).unwrap();
+
+        let header = b"P6\n256 256\n255\n";
+        assert_eq!(&out[..header.len()], &header[..]);
+        assert_eq!(out.len(), header.len() + 256 * 256 * 3);
+        let pixels = &out[header.len()..];
+        // address 0: hottest in both reads and writes
+        assert_eq!(&pixels[0..3], &[255, 255, 0]);
+        // address 1: written once, never read
+        let cell1 = CELL_SIZE * 3;
+        assert!(pixels[cell1] > 0 && pixels[cell1] < 255);
+        assert_eq!(pixels[cell1 + 1], 0);
+    }
+}
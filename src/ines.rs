@@ -12,12 +12,24 @@ use ppu::Mirroring;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
+#[derive(Debug, PartialEq)]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+}
+
 #[derive(Debug)]
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    // no CHR ROM on the board, chr_rom is 8KB of writable memory instead
+    pub chr_ram: bool,
     pub mapper: u8,
     pub mirroring: Mirroring,
+    // battery-backed PRG RAM at $6000-$7FFF
+    pub battery: bool,
+    // flags 9, rarely set by dumps
+    pub tv_system: TvSystem,
 }
 
 impl Rom {
@@ -44,9 +56,13 @@ impl Rom {
             (false, false) => Mirroring::Horizontal,
         };
 
+        let battery = raw[6] & 0b10 != 0;
+        let tv_system = if raw[9] & 0b1 != 0 { TvSystem::Pal } else { TvSystem::Ntsc };
+
         // PRG/CHR ROM size
         let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
         let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let chr_ram = chr_rom_size == 0;
 
         // trainer (used to run programs on different hardwares)
         let is_exist_trainer = raw[6] & 0b100 == 0;
@@ -56,9 +72,50 @@ impl Rom {
 
         Ok(Rom {
             prg_rom: raw[prg_rom_begin..(prg_rom_begin + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_begin..(chr_rom_begin + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            mirroring: mirroring,
+            chr_rom: if chr_ram {
+                vec![0; CHR_ROM_PAGE_SIZE]
+            } else {
+                raw[chr_rom_begin..(chr_rom_begin + chr_rom_size)].to_vec()
+            },
+            chr_ram,
+            mapper,
+            mirroring,
+            battery,
+            tv_system,
+        })
+    }
+
+    // NROM cartridge around a headerless 6502 binary
+    // a program loaded at $8000 or above is placed in PRG ROM, anything lower has to be
+    // written to RAM afterwards (Cpu::load), the PRG ROM then only holds the reset vector
+    pub fn from_binary(program: &[u8], load_addr: u16, reset_vector: u16, chr: Option<&[u8]>) -> Result<Rom, String> {
+        let mut prg_rom = vec![0; 2 * PRG_ROM_PAGE_SIZE];
+        if load_addr >= 0x8000 {
+            let begin = load_addr as usize - 0x8000;
+            if begin + program.len() > prg_rom.len() {
+                return Err(format!("{} bytes don't fit at ${:04X}", program.len(), load_addr));
+            }
+            prg_rom[begin..begin + program.len()].copy_from_slice(program);
+        }
+        prg_rom[0x7ffc] = reset_vector as u8;
+        prg_rom[0x7ffd] = (reset_vector >> 8) as u8;
+
+        let mut chr_rom = vec![0; CHR_ROM_PAGE_SIZE];
+        if let Some(chr) = chr {
+            if chr.len() > CHR_ROM_PAGE_SIZE {
+                return Err(format!("CHR data is {} bytes, at most 8192 are supported", chr.len()));
+            }
+            chr_rom[..chr.len()].copy_from_slice(chr);
+        }
+
+        Ok(Rom {
+            prg_rom,
+            chr_rom,
+            chr_ram: chr.is_none(),
+            mapper: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            tv_system: TvSystem::Ntsc,
         })
     }
 }
@@ -91,15 +148,107 @@ pub mod test {
         res
     }
 
-    pub fn create_rom() -> Rom {
-        let raw = create_raw(TestRom {
-            header: vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,],
-            trainer: None,
-            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
-        });
+    // builds cartridges for tests
+    // PRG code starts at $8000 and the reset vector points there, unused PRG is BRK
+    pub struct TestRomBuilder {
+        prg: Vec<u8>,
+        chr: Vec<u8>,
+        prg_banks: u8,
+        chr_banks: u8,
+        mapper: u8,
+        mirroring: Mirroring,
+        battery: bool,
+    }
+
+    impl TestRomBuilder {
+        pub fn new() -> Self {
+            TestRomBuilder {
+                prg: vec![],
+                chr: vec![],
+                prg_banks: 2,
+                chr_banks: 1,
+                mapper: 0,
+                mirroring: Mirroring::Horizontal,
+                battery: false,
+            }
+        }
+
+        pub fn prg(mut self, code: &[u8]) -> Self {
+            self.prg = code.to_vec();
+            self
+        }
+
+        pub fn chr(mut self, tiles: &[u8]) -> Self {
+            self.chr = tiles.to_vec();
+            self
+        }
+
+        pub fn prg_banks(mut self, banks: u8) -> Self {
+            self.prg_banks = banks;
+            self
+        }
+
+        pub fn chr_banks(mut self, banks: u8) -> Self {
+            self.chr_banks = banks;
+            self
+        }
 
-        Rom::analyze_raw(&raw).unwrap()
+        pub fn mapper(mut self, mapper: u8) -> Self {
+            self.mapper = mapper;
+            self
+        }
+
+        pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+            self.mirroring = mirroring;
+            self
+        }
+
+        pub fn battery(mut self, battery: bool) -> Self {
+            self.battery = battery;
+            self
+        }
+
+        pub fn raw(&self) -> Vec<u8> {
+            let mut flags6 = (self.mapper & 0b1111) << 4;
+            flags6 |= match self.mirroring {
+                Mirroring::Vertical => 0b1,
+                Mirroring::Horizontal => 0b0,
+                Mirroring::FourScreen => 0b1000,
+                // chosen by the mapper, not the header
+                Mirroring::SingleScreenA | Mirroring::SingleScreenB => 0b0,
+            };
+            if self.battery {
+                flags6 |= 0b10;
+            }
+            let flags7 = self.mapper & 0b1111_0000;
+            let header = vec![0x4E, 0x45, 0x53, 0x1A, self.prg_banks, self.chr_banks, flags6, flags7,
+                00, 00, 00, 00, 00, 00, 00, 00];
+
+            let mut prg_rom = vec![0; self.prg_banks as usize * PRG_ROM_PAGE_SIZE];
+            prg_rom[..self.prg.len()].copy_from_slice(&self.prg);
+            // reset vector, the last bank is mapped to $C000-$FFFF
+            let len = prg_rom.len();
+            prg_rom[len - 4] = 0x00;
+            prg_rom[len - 3] = 0x80;
+
+            let mut chr_rom = vec![0; self.chr_banks as usize * CHR_ROM_PAGE_SIZE];
+            chr_rom[..self.chr.len()].copy_from_slice(&self.chr);
+
+            create_raw(TestRom {
+                header,
+                trainer: None,
+                prg_rom,
+                chr_rom,
+            })
+        }
+
+        pub fn build(&self) -> Rom {
+            Rom::analyze_raw(&self.raw()).unwrap()
+        }
+    }
+
+    pub fn create_rom() -> Rom {
+        TestRomBuilder::new().build()
     }
 
     #[test]
@@ -118,6 +267,52 @@ pub mod test {
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
+        assert_eq!(rom.tv_system, TvSystem::Ntsc);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let rom = TestRomBuilder::new().chr_banks(0).build();
+        assert!(rom.chr_ram);
+        assert_eq!(rom.chr_rom, vec![0; CHR_ROM_PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_builder() {
+        let rom = TestRomBuilder::new()
+            .prg(&[0xa9, 0x05])
+            .chr(&[0xff; 16])
+            .prg_banks(1)
+            .mapper(0x42)
+            .mirroring(Mirroring::Vertical)
+            .battery(true)
+            .build();
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(&rom.prg_rom[0..3], &[0xa9, 0x05, 0x00]);
+        assert_eq!(&rom.prg_rom[0x3ffc..0x3ffe], &[0x00, 0x80]);
+        assert_eq!(&rom.chr_rom[0..16], &[0xff; 16]);
+        assert_eq!(rom.chr_rom[16], 0);
+        assert_eq!(rom.mapper, 0x42);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+    }
+
+    #[test]
+    fn test_from_binary() {
+        let rom = Rom::from_binary(&[0xa9, 0x05], 0xc000, 0xc000, None).unwrap();
+        assert_eq!(&rom.prg_rom[0x4000..0x4003], &[0xa9, 0x05, 0x00]);
+        assert_eq!(&rom.prg_rom[0x7ffc..0x7ffe], &[0x00, 0xc0]);
+        assert!(rom.chr_ram);
+
+        let rom = Rom::from_binary(&[0xa9, 0x05], 0x0600, 0x0600, Some(&[0xff; 16])).unwrap();
+        assert_eq!(rom.prg_rom[0], 0);
+        assert_eq!(&rom.prg_rom[0x7ffc..0x7ffe], &[0x00, 0x06]);
+        assert_eq!(&rom.chr_rom[..16], &[0xff; 16]);
+        assert_eq!(rom.chr_rom[16], 0);
+        assert!(!rom.chr_ram);
+
+        assert!(Rom::from_binary(&[0; 3], 0xfffe, 0x8000, None).is_err());
     }
 
 }
\ No newline at end of file
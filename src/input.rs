@@ -0,0 +1,189 @@
+// host keys turned into joypad input once per emulated frame: turbo fire and hotkey
+// repeat are counted in frames, so they follow the game's speed instead of the host's
+// key repeat or wall clock, and every frame's buttons are known up front
+
+use controller::JoypadButton;
+
+// turbo buttons are pressed for TURBO_FRAMES frames, then released for as many
+pub const TURBO_FRAMES: usize = 2;
+// a held hotkey fires again after REPEAT_DELAY frames, then every REPEAT_FRAMES
+pub const REPEAT_DELAY: usize = 20;
+pub const REPEAT_FRAMES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    // player, button
+    Button(usize, JoypadButton),
+    Turbo(usize, JoypadButton),
+    // Famicom controller 2 microphone
+    Microphone,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Hotkey<K> {
+    pub key: K,
+    // fired again by holding the key down
+    pub repeat: bool,
+}
+
+// what the next frame sees
+#[derive(Debug, PartialEq)]
+pub struct FrameInput<K> {
+    pub buttons: [JoypadButton; 2],
+    pub microphone: bool,
+    // unbound keys that went down or repeat, in the order they were pressed
+    pub hotkeys: Vec<Hotkey<K>>,
+}
+
+// what a frontend reports between frames
+#[derive(Debug, PartialEq)]
+pub enum HostEvent<K> {
+    Quit,
+    KeyDown(K),
+    KeyUp(K),
+}
+
+// where host events come from, e.g. an SDL window or the terminal
+pub trait EventSource<K> {
+    // events since the last call, without blocking
+    fn poll(&mut self) -> Vec<HostEvent<K>>;
+    // blocks until at least one event arrives
+    fn wait(&mut self) -> Vec<HostEvent<K>>;
+}
+
+struct Held<K> {
+    key: K,
+    // frame the key went down before
+    since: usize,
+    // let go before any frame saw it, the next frame still does so short taps aren't lost
+    released: bool,
+}
+
+pub struct Input<K> {
+    bindings: Vec<(K, Binding)>,
+    held: Vec<Held<K>>,
+    frame: usize,
+}
+
+impl<K: Copy + Eq> Input<K> {
+    pub fn new(bindings: Vec<(K, Binding)>) -> Self {
+        Input {
+            bindings,
+            held: vec![],
+            frame: 0,
+        }
+    }
+
+    // host key repeats of a held key are ignored
+    pub fn key_down(&mut self, key: K) {
+        match self.held.iter_mut().find(|h| h.key == key) {
+            Some(held) => held.released = false,
+            None => self.held.push(Held { key, since: self.frame, released: false }),
+        }
+    }
+
+    pub fn key_up(&mut self, key: K) {
+        let frame = self.frame;
+        if let Some(held) = self.held.iter_mut().find(|h| h.key == key && h.since == frame) {
+            held.released = true;
+        }
+        self.held.retain(|h| h.key != key || h.released);
+    }
+
+    // input for the next emulated frame, called once per frame
+    pub fn next_frame(&mut self) -> FrameInput<K> {
+        let mut input = FrameInput {
+            buttons: [JoypadButton::empty(); 2],
+            microphone: false,
+            hotkeys: vec![],
+        };
+        for held in self.held.iter() {
+            let frames = self.frame - held.since;
+            match self.bindings.iter().find(|&&(key, _)| key == held.key).map(|&(_, b)| b) {
+                Some(Binding::Button(player, button)) => input.buttons[player].insert(button),
+                Some(Binding::Turbo(player, button)) => {
+                    if (frames / TURBO_FRAMES).is_multiple_of(2) {
+                        input.buttons[player].insert(button);
+                    }
+                },
+                Some(Binding::Microphone) => input.microphone = true,
+                None => {
+                    let repeat = frames >= REPEAT_DELAY && (frames - REPEAT_DELAY).is_multiple_of(REPEAT_FRAMES);
+                    if frames == 0 || repeat {
+                        input.hotkeys.push(Hotkey { key: held.key, repeat });
+                    }
+                },
+            }
+        }
+        self.held.retain(|h| !h.released);
+        self.frame += 1;
+        input
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input() -> Input<char> {
+        Input::new(vec![
+            ('a', Binding::Button(0, JoypadButton::A)),
+            ('q', Binding::Turbo(0, JoypadButton::A)),
+            ('o', Binding::Turbo(1, JoypadButton::B)),
+            ('m', Binding::Microphone),
+        ])
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut input = input();
+        input.key_down('a');
+        input.key_down('m');
+        let frame = input.next_frame();
+        assert_eq!(frame.buttons, [JoypadButton::A, JoypadButton::empty()]);
+        assert!(frame.microphone);
+        assert!(frame.hotkeys.is_empty());
+        input.key_up('m');
+        assert!(!input.next_frame().microphone);
+        // a tap between two frames still reaches the game
+        input.key_up('a');
+        input.key_down('m');
+        input.key_up('m');
+        let frame = input.next_frame();
+        assert!(frame.microphone);
+        assert_eq!(frame.buttons[0], JoypadButton::empty());
+        assert!(!input.next_frame().microphone);
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut input = input();
+        input.next_frame();
+        input.key_down('o');
+        let pressed: Vec<bool> = (0..8).map(|_| input.next_frame().buttons[1].contains(JoypadButton::B)).collect();
+        assert_eq!(pressed, vec![true, true, false, false, true, true, false, false]);
+        // host key repeats don't restart the cycle
+        input.key_down('o');
+        assert!(input.next_frame().buttons[1].contains(JoypadButton::B));
+    }
+
+    #[test]
+    fn test_hotkey_repeat() {
+        let mut input = input();
+        input.key_down('x');
+        input.key_down('y');
+        let fired: Vec<Vec<Hotkey<char>>> = (0..REPEAT_DELAY + REPEAT_FRAMES + 1).map(|_| input.next_frame().hotkeys).collect();
+        assert_eq!(fired[0], vec![Hotkey { key: 'x', repeat: false }, Hotkey { key: 'y', repeat: false }]);
+        for (frame, hotkeys) in fired.iter().enumerate().skip(1) {
+            if frame == REPEAT_DELAY || frame == REPEAT_DELAY + REPEAT_FRAMES {
+                assert_eq!(hotkeys, &vec![Hotkey { key: 'x', repeat: true }, Hotkey { key: 'y', repeat: true }]);
+            } else {
+                assert!(hotkeys.is_empty());
+            }
+        }
+        input.key_up('x');
+        input.key_up('y');
+        input.key_down('x');
+        assert_eq!(input.next_frame().hotkeys, vec![Hotkey { key: 'x', repeat: false }]);
+    }
+}
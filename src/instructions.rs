@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use cpu::AddressingMode;
-use std::collections::HashMap;
+use cpu::ops::{self, Handler};
 
 pub struct Instruction {
     pub opcode: u8,
@@ -8,16 +8,18 @@ pub struct Instruction {
     pub len: u8,
     pub cycles: u8,
     pub mode: AddressingMode,
+    pub handler: Handler,
 }
 
 impl Instruction {
     fn new(opcode: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self {
         Instruction {
-            opcode: opcode,
-            mnemonic: mnemonic,
-            len: len,
-            cycles: cycles,
-            mode: mode
+            opcode,
+            mnemonic,
+            len,
+            cycles,
+            mode,
+            handler: ops::handler(mnemonic),
         }
     } 
 }
@@ -114,25 +116,25 @@ lazy_static! {
         Instruction::new(0xe1, "SBC", 2, 6, AddressingMode::IndirectX),
         Instruction::new(0xf1, "SBC", 2, 5/*+1 if page crossed */, AddressingMode::IndirectY),
 
-        Instruction::new(0x0a, "ASL", 1, 2, AddressingMode::Implied), // accumulator
+        Instruction::new(0x0a, "ASL", 1, 2, AddressingMode::Accumulator),
         Instruction::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
         Instruction::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPageX),
         Instruction::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute),
         Instruction::new(0x1e, "ASL", 3, 7, AddressingMode::AbsoluteX),
 
-        Instruction::new(0x4a, "LSR", 1, 2, AddressingMode::Implied), // accumulator
+        Instruction::new(0x4a, "LSR", 1, 2, AddressingMode::Accumulator),
         Instruction::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
         Instruction::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPageX),
         Instruction::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute),
         Instruction::new(0x5e, "LSR", 3, 7, AddressingMode::AbsoluteX),
 
-        Instruction::new(0x2a, "ROL", 1, 2, AddressingMode::Implied), // accumulator
+        Instruction::new(0x2a, "ROL", 1, 2, AddressingMode::Accumulator),
         Instruction::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
         Instruction::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPageX),
         Instruction::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute),
         Instruction::new(0x3e, "ROL", 3, 7, AddressingMode::AbsoluteX),
 
-        Instruction::new(0x6a, "ROR", 1, 2, AddressingMode::Implied), // accumulator
+        Instruction::new(0x6a, "ROR", 1, 2, AddressingMode::Accumulator),
         Instruction::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
         Instruction::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPageX),
         Instruction::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute),
@@ -182,8 +184,8 @@ lazy_static! {
         Instruction::new(0x40, "RTI", 1, 6, AddressingMode::Implied),
         Instruction::new(0x60, "RTS", 1, 6, AddressingMode::Implied),
 
-        Instruction::new(0x4c, "JMP", 3, 5, AddressingMode::Absolute),
-        Instruction::new(0x6c, "JMP", 3, 5, AddressingMode::Implied),
+        Instruction::new(0x4c, "JMP", 3, 3, AddressingMode::Absolute),
+        Instruction::new(0x6c, "JMP", 3, 5, AddressingMode::Indirect),
         Instruction::new(0x20, "JSR", 3, 6, AddressingMode::Absolute),
 
         Instruction::new(0x90, "BCC", 2, 2, AddressingMode::Relative),
@@ -239,11 +241,11 @@ lazy_static! {
         Instruction::new(0x43, "*SRE", 2, 8, AddressingMode::IndirectX),
         Instruction::new(0x53, "*SRE", 2, 8, AddressingMode::IndirectY),
 
-        Instruction::new(0x80, "*SKB", 2,2, AddressingMode::Immediate),
-        Instruction::new(0x82, "*SKB", 2,2, AddressingMode::Immediate),
-        Instruction::new(0x89, "*SKB", 2,2, AddressingMode::Immediate),
-        Instruction::new(0xc2, "*SKB", 2,2, AddressingMode::Immediate),
-        Instruction::new(0xe2, "*SKB", 2,2, AddressingMode::Immediate),
+        Instruction::new(0x80, "*NOP", 2,2, AddressingMode::Immediate),
+        Instruction::new(0x82, "*NOP", 2,2, AddressingMode::Immediate),
+        Instruction::new(0x89, "*NOP", 2,2, AddressingMode::Immediate),
+        Instruction::new(0xc2, "*NOP", 2,2, AddressingMode::Immediate),
+        Instruction::new(0xe2, "*NOP", 2,2, AddressingMode::Immediate),
 
         Instruction::new(0xCB, "*AXS", 2,2, AddressingMode::Immediate),
 
@@ -266,12 +268,12 @@ lazy_static! {
         Instruction::new(0xd4, "*NOP", 2, 4, AddressingMode::ZeroPageX),
         Instruction::new(0xf4, "*NOP", 2, 4, AddressingMode::ZeroPageX),
         Instruction::new(0x0c, "*NOP", 3, 4, AddressingMode::Absolute),
-        Instruction::new(0x1c, "*NOP", 3, 4 /*or 5*/, AddressingMode::AbsoluteX),
-        Instruction::new(0x3c, "*NOP", 3, 4 /*or 5*/, AddressingMode::AbsoluteX),
-        Instruction::new(0x5c, "*NOP", 3, 4 /*or 5*/, AddressingMode::AbsoluteX),
-        Instruction::new(0x7c, "*NOP", 3, 4 /*or 5*/, AddressingMode::AbsoluteX),
-        Instruction::new(0xdc, "*NOP", 3, 4 /* or 5*/, AddressingMode::AbsoluteX),
-        Instruction::new(0xfc, "*NOP", 3, 4 /* or 5*/, AddressingMode::AbsoluteX),
+        Instruction::new(0x1c, "*NOP", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
+        Instruction::new(0x3c, "*NOP", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
+        Instruction::new(0x5c, "*NOP", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
+        Instruction::new(0x7c, "*NOP", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
+        Instruction::new(0xdc, "*NOP", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
+        Instruction::new(0xfc, "*NOP", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
 
         Instruction::new(0x67, "*RRA", 2, 5, AddressingMode::ZeroPage),
         Instruction::new(0x77, "*RRA", 2, 6, AddressingMode::ZeroPageX),
@@ -289,18 +291,18 @@ lazy_static! {
         Instruction::new(0xe3, "*ISB", 2,8, AddressingMode::IndirectX),
         Instruction::new(0xf3, "*ISB", 2,8, AddressingMode::IndirectY),
 
-        Instruction::new(0x02, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x12, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x22, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x32, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x42, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x52, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x62, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x72, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0x92, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0xb2, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0xd2, "*NOP", 1,2, AddressingMode::Implied),
-        Instruction::new(0xf2, "*NOP", 1,2, AddressingMode::Implied),
+        Instruction::new(0x02, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x12, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x22, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x32, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x42, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x52, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x62, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x72, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0x92, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0xb2, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0xd2, "*JAM", 1,2, AddressingMode::Implied),
+        Instruction::new(0xf2, "*JAM", 1,2, AddressingMode::Implied),
 
         Instruction::new(0x1a, "*NOP", 1, 2, AddressingMode::Implied),
         Instruction::new(0x3a, "*NOP", 1, 2, AddressingMode::Implied),
@@ -309,33 +311,34 @@ lazy_static! {
         Instruction::new(0xda, "*NOP", 1, 2, AddressingMode::Implied),
         Instruction::new(0xfa, "*NOP", 1, 2, AddressingMode::Implied),
 
-        Instruction::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate), // TODO: used?
-        Instruction::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate), // TODO: used?
-        Instruction::new(0xbb, "*LAS", 3, 2, AddressingMode::AbsoluteX), // TODO: used?
-        Instruction::new(0x9b, "*TAS", 3, 2, AddressingMode::AbsoluteX), // TODO: used?
-        Instruction::new(0x93, "*AHX", 2, /* TODO: correct? */ 8, AddressingMode::IndirectX), // TODO: used?
-        Instruction::new(0x9f, "*AHX", 3, /* TODO: correct? */ 4/* or 5*/, AddressingMode::AbsoluteX), // TODO: used?
-        Instruction::new(0x9e, "*SHX", 3, /* TODO: correct? */ 4/* or 5*/, AddressingMode::AbsoluteX), // TODO: used?
-        Instruction::new(0x9c, "*SHY", 3, /* TODO: correct? */ 4/* or 5*/, AddressingMode::AbsoluteX), // TODO: used?
+        Instruction::new(0xab, "*LXA", 2, 2, AddressingMode::Immediate),
+        Instruction::new(0x8b, "*XAA", 2, 2, AddressingMode::Immediate),
+        Instruction::new(0xbb, "*LAS", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteY),
+        Instruction::new(0x9b, "*TAS", 3, 5, AddressingMode::AbsoluteY),
+        Instruction::new(0x93, "*AHX", 2, 6, AddressingMode::IndirectY),
+        Instruction::new(0x9f, "*AHX", 3, 5, AddressingMode::AbsoluteY),
+        Instruction::new(0x9e, "*SHX", 3, 5, AddressingMode::AbsoluteY),
+        Instruction::new(0x9c, "*SHY", 3, 5, AddressingMode::AbsoluteX),
 
         Instruction::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage),
-        Instruction::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPageX),
+        Instruction::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPageY),
         Instruction::new(0xaf, "*LAX", 3, 4, AddressingMode::Absolute),
-        Instruction::new(0xbf, "*LAX", 3, 4, AddressingMode::AbsoluteX),
+        Instruction::new(0xbf, "*LAX", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteY),
         Instruction::new(0xa3, "*LAX", 2, 6, AddressingMode::IndirectX),
-        Instruction::new(0xb3, "*LAX", 2, 5, AddressingMode::IndirectX),
+        Instruction::new(0xb3, "*LAX", 2, 5/*+1 if page crossed*/, AddressingMode::IndirectY),
 
         Instruction::new(0x87, "*SAX", 2, 3, AddressingMode::ZeroPage),
-        Instruction::new(0x97, "*SAX", 2, 4, AddressingMode::ZeroPageX),
+        Instruction::new(0x97, "*SAX", 2, 4, AddressingMode::ZeroPageY),
         Instruction::new(0x8f, "*SAX", 3, 4, AddressingMode::Absolute),
         Instruction::new(0x83, "*SAX", 2, 6, AddressingMode::IndirectX),
     ];
 
-    pub static ref INSTRUCTION_MAP: HashMap<u8, &'static Instruction> = {
-        let mut map = HashMap::new();
+    // indexed by opcode, None for opcodes missing from CPU_INSTRUCTIONS
+    pub static ref INSTRUCTION_TABLE: [Option<&'static Instruction>; 256] = {
+        let mut table = [None; 256];
         for cpu_inst in &*CPU_INSTRUCTIONS {
-            map.insert(cpu_inst.opcode, cpu_inst);
+            table[cpu_inst.opcode as usize] = Some(cpu_inst);
         }
-        map
+        table
     };
 }
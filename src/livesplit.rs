@@ -0,0 +1,54 @@
+use std::io;
+use std::io::Write;
+use std::net::TcpStream;
+
+// client for the LiveSplit Server component
+// commands are plain text lines terminated by \r\n
+pub struct LiveSplit {
+    stream: TcpStream,
+}
+
+impl LiveSplit {
+    pub fn connect(addr: &str) -> io::Result<LiveSplit> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(LiveSplit { stream })
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<()> {
+        self.stream.write_all(command.as_bytes())?;
+        self.stream.write_all(b"\r\n")
+    }
+
+    // starts the timer on the first call, splits afterwards
+    pub fn start_or_split(&mut self) -> io::Result<()> {
+        self.send("startorsplit")
+    }
+
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.send("reset")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let mut livesplit = LiveSplit::connect(&addr).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        livesplit.start_or_split().unwrap();
+        livesplit.reset().unwrap();
+        drop(livesplit);
+
+        let mut received = String::new();
+        server.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "startorsplit\r\nreset\r\n");
+    }
+}
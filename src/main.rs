@@ -5,24 +5,45 @@ mod memory;
 mod ines;
 mod trace;
 mod ppu;
+mod apu;
 mod tile;
 mod render;
 mod controller;
+mod heatmap;
+mod condition;
+mod livesplit;
+mod video;
+mod cartridge;
+mod patch;
+mod fuzz;
+mod opstats;
+mod report;
+mod bench;
+mod verify;
+mod testrom;
+mod devlog;
+mod input;
+mod state;
 #[macro_use]
 extern crate lazy_static;
 extern crate bitflags;
 extern crate sdl2;
+extern crate rand;
 use std::path::Path;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use memory::Mem;
-use std::collections::HashMap;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::time::Instant;
+use std::rc::Rc;
+use std::cell::Cell;
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
 use sdl2::EventPump;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
-use sdl2::pixels::PixelFormatEnum;
 use sdl2::sys::exit;
 
 fn handle_user_input(cpu: &mut cpu::Cpu, event_pump: &mut EventPump) {
@@ -99,84 +120,731 @@ fn read_screen_state(cpu: &cpu::Cpu, frame: &mut [u8; 32 * 3 * 32]) -> bool {
 }
 */
 
+fn watch_arg(arg: Option<&String>) -> condition::Watch {
+    let expr = arg.map(|s| s.as_str()).unwrap_or("");
+    match condition::Watch::new(expr) {
+        Ok(watch) => watch,
+        Err(msg) => {
+            println!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+}
+
+// ADDR=VALUE
+fn poke_arg(arg: Option<&String>) -> (u16, u8) {
+    let expr = arg.map(|s| s.as_str()).unwrap_or("");
+    let parsed = match expr.find('=') {
+        Some(pos) => condition::parse_number(&expr[..pos])
+            .and_then(|addr| condition::parse_number(&expr[pos + 1..]).map(|value| (addr, value))),
+        None => Err(format!("expected ADDR=VALUE: `{}`", expr)),
+    };
+    match parsed {
+        Ok((addr, value)) if addr <= 0xffff && value <= 0xff => (addr as u16, value as u8),
+        Ok(_) => {
+            println!("poke out of range: `{}`", expr);
+            std::process::exit(1);
+        },
+        Err(msg) => {
+            println!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn addr_arg(name: &str, arg: Option<&String>) -> u16 {
+    match arg.map(|s| condition::parse_number(s)) {
+        Some(Ok(addr)) if addr <= 0xffff => addr as u16,
+        _ => {
+            println!("{} expects an address", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+// joypad buttons in the order --keys1/--keys2 list them
+const KEY_ORDER: [controller::JoypadButton; 8] = [
+    controller::JoypadButton::UP,
+    controller::JoypadButton::DOWN,
+    controller::JoypadButton::LEFT,
+    controller::JoypadButton::RIGHT,
+    controller::JoypadButton::A,
+    controller::JoypadButton::B,
+    controller::JoypadButton::SELECT,
+    controller::JoypadButton::START,
+];
+
+// turbo A/B of player 1 and 2: key, player, button
+const TURBO_KEYS: [(Keycode, usize, controller::JoypadButton); 4] = [
+    (Keycode::Q, 0, controller::JoypadButton::A),
+    (Keycode::W, 0, controller::JoypadButton::B),
+    (Keycode::U, 1, controller::JoypadButton::A),
+    (Keycode::O, 1, controller::JoypadButton::B),
+];
+
+// comma separated SDL key names, e.g. "Up,Down,Left,Right,A,S,Space,Return"
+fn keys_arg(arg: Option<&String>) -> Vec<Keycode> {
+    let names: Vec<&str> = arg.map(|s| s.split(',').collect()).unwrap_or_default();
+    let keys: Vec<Keycode> = names.iter().filter_map(|name| Keycode::from_name(name)).collect();
+    if keys.len() != KEY_ORDER.len() {
+        println!("expected 8 key names (up,down,left,right,a,b,select,start): {}",
+            arg.map(|s| s.as_str()).unwrap_or(""));
+        std::process::exit(1);
+    }
+    keys
+}
+
+// F1/F2 gamma, F3/F4 brightness, F5/F6 saturation down/up
+// returns false for keys that are not picture controls
+fn adjust_color(color: &mut render::palette::ColorAdjust, key: Keycode) -> bool {
+    let (value, step) = match key {
+        Keycode::F1 => (&mut color.gamma, -0.1),
+        Keycode::F2 => (&mut color.gamma, 0.1),
+        Keycode::F3 => (&mut color.brightness, -0.1),
+        Keycode::F4 => (&mut color.brightness, 0.1),
+        Keycode::F5 => (&mut color.saturation, -0.1),
+        Keycode::F6 => (&mut color.saturation, 0.1),
+        _ => return false,
+    };
+    *value = (*value + step).clamp(0.1, 3.0);
+    true
+}
+
+// write to <path>.tmp and rename it over the old file, so a crash or a full disk
+// while writing leaves the previous save intact
+fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+fn color_arg(name: &str, arg: Option<&String>) -> f32 {
+    match arg.and_then(|n| n.parse().ok()) {
+        Some(value) if value > 0.0 => value,
+        _ => {
+            println!("{} expects a positive number", name);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     println!("NES emulator");
     let args: Vec<String> = env::args().collect();
-    
-    if args.len() < 2 {
-        println!("usage: nes-emu <file path>");
-        std::process::exit(0);
-    }
-
-    // init sdl2
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsys = sdl_context.video().unwrap();
-    let window = video_subsys
-        .window("test", 256 * 3, 240 * 3)
-        .position_centered()
-        .build().unwrap();
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
-
-    // create texture
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24,256, 240).unwrap();
-    
+
+    // subcommands run without a window and exit when done
+    match args.get(1).map(|s| s.as_str()) {
+        Some("report") => report::run(&args),
+        Some("bench-rom") => bench::run_generate(&args),
+        Some("bench") => bench::run(&args),
+        Some("test-rom") => testrom::run(&args),
+        Some("fuzz") => fuzz::run(&args),
+        Some("verify") => verify::run(&args),
+        _ => (),
+    }
+
+    let mut rom_path: Option<String> = None;
+    let mut perf_log_path: Option<String> = None;
+    let mut compare_log_path: Option<String> = None;
+    let mut trace_path: Option<String> = None;
+    let mut trace_json = false;
+    let mut heatmap_path: Option<String> = None;
+    let mut opcode_stats_path: Option<String> = None;
+    let mut watches: Vec<condition::Watch> = vec![];
+    let mut splits: Vec<condition::Watch> = vec![];
+    let mut livesplit_addr: Option<String> = None;
+    let mut video_backend = String::from("sdl");
+    let mut pause_at: Vec<u64> = vec![];
+    let mut patch_paths: Vec<String> = vec![];
+    let mut auto_patch = true;
+    let mut panning: Option<apu::Panning> = None;
+    let mut overclock: u16 = 0;
+    let mut sprite_limit = true;
+    let mut dev_log = false;
+    let mut color = render::palette::ColorAdjust::new();
+    let mut palette_arg: Option<String> = None;
+    let mut filter: Option<render::scale::Filter> = None;
+    // headless binary: load address, reset vector and CHR file
+    let mut raw_load: Option<u16> = None;
+    let mut raw_reset: Option<u16> = None;
+    let mut chr_path: Option<String> = None;
+    let mut pokes: Vec<(u16, u8)> = vec![];
+    let mut keys = [
+        vec![Keycode::Up, Keycode::Down, Keycode::Left, Keycode::Right,
+            Keycode::A, Keycode::S, Keycode::Space, Keycode::Return],
+        vec![Keycode::I, Keycode::K, Keycode::J, Keycode::L,
+            Keycode::Period, Keycode::Comma, Keycode::RShift, Keycode::Backspace],
+    ];
+    // held to blow into the Famicom's controller 2 microphone
+    let mut mic_key = Keycode::M;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--perf-log" => {
+                i += 1;
+                perf_log_path = args.get(i).cloned();
+            },
+            "--compare-log" => {
+                i += 1;
+                compare_log_path = args.get(i).cloned();
+            },
+            "--trace" => {
+                i += 1;
+                trace_path = args.get(i).cloned();
+            },
+            "--trace-format" => {
+                i += 1;
+                match args.get(i).map(|s| s.as_str()) {
+                    Some("text") => trace_json = false,
+                    Some("json") => trace_json = true,
+                    _ => {
+                        println!("--trace-format expects text or json");
+                        std::process::exit(1);
+                    }
+                }
+            },
+            "--heatmap" => {
+                i += 1;
+                heatmap_path = args.get(i).cloned();
+            },
+            "--opcode-stats" => {
+                i += 1;
+                opcode_stats_path = args.get(i).cloned();
+            },
+            "--watch" => {
+                i += 1;
+                watches.push(watch_arg(args.get(i)));
+            },
+            "--split" => {
+                i += 1;
+                splits.push(watch_arg(args.get(i)));
+            },
+            "--livesplit" => {
+                i += 1;
+                livesplit_addr = args.get(i).cloned();
+            },
+            "--patch" => {
+                i += 1;
+                patch_paths.extend(args.get(i).cloned());
+            },
+            "--no-auto-patch" => auto_patch = false,
+            "--overclock" => {
+                i += 1;
+                overclock = match args.get(i).and_then(|n| n.parse().ok()) {
+                    Some(lines) => lines,
+                    None => {
+                        println!("--overclock expects a number of scanlines");
+                        std::process::exit(1);
+                    }
+                };
+            },
+            "--no-sprite-limit" => sprite_limit = false,
+            "--dev-log" => dev_log = true,
+            "--stereo" => panning = Some(apu::Panning::split_pulses()),
+            "--pan" => {
+                i += 1;
+                match apu::Panning::parse(args.get(i).map(|s| s.as_str()).unwrap_or("")) {
+                    Ok(p) => panning = Some(p),
+                    Err(msg) => {
+                        println!("{}", msg);
+                        std::process::exit(1);
+                    }
+                }
+            },
+            "--poke" => {
+                i += 1;
+                pokes.push(poke_arg(args.get(i)));
+            },
+            "--pause-at" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse().ok()) {
+                    Some(frame) => pause_at.push(frame),
+                    None => {
+                        println!("--pause-at expects a frame number");
+                        std::process::exit(1);
+                    }
+                }
+            },
+            "--keys1" => {
+                i += 1;
+                keys[0] = keys_arg(args.get(i));
+            },
+            "--keys2" => {
+                i += 1;
+                keys[1] = keys_arg(args.get(i));
+            },
+            "--mic-key" => {
+                i += 1;
+                match args.get(i).and_then(|name| Keycode::from_name(name)) {
+                    Some(key) => mic_key = key,
+                    None => {
+                        println!("invalid key name: {}", args.get(i).map(|s| s.as_str()).unwrap_or(""));
+                        std::process::exit(1);
+                    }
+                }
+            },
+            "--filter" => {
+                i += 1;
+                match args.get(i).and_then(|name| render::scale::Filter::parse(name)) {
+                    Some(f) => filter = Some(f),
+                    None => {
+                        println!("--filter expects nearest or scanlines");
+                        std::process::exit(1);
+                    }
+                }
+            },
+            "--palette" => {
+                i += 1;
+                palette_arg = args.get(i).cloned();
+            },
+            "--gamma" => {
+                i += 1;
+                color.gamma = color_arg("--gamma", args.get(i));
+            },
+            "--brightness" => {
+                i += 1;
+                color.brightness = color_arg("--brightness", args.get(i));
+            },
+            "--saturation" => {
+                i += 1;
+                color.saturation = color_arg("--saturation", args.get(i));
+            },
+            "--raw" => {
+                i += 1;
+                raw_load = Some(addr_arg("--raw", args.get(i)));
+            },
+            "--reset" => {
+                i += 1;
+                raw_reset = Some(addr_arg("--reset", args.get(i)));
+            },
+            "--chr" => {
+                i += 1;
+                chr_path = args.get(i).cloned();
+            },
+            "--video" => {
+                i += 1;
+                video_backend = args.get(i).cloned().unwrap_or_default();
+            },
+            path => rom_path = Some(path.to_string()),
+        }
+        i += 1;
+    }
+
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => {
+            println!("usage: nes-emu [--perf-log <csv path>] [--compare-log <trace log>] [--trace <path> [--trace-format text|json]] [--heatmap <ppm path>] [--opcode-stats <csv path>] [--watch <condition>]... [--livesplit <host:port> --split <condition>...] [--patch <ips/bps path>]... [--no-auto-patch] [--stereo | --pan <p1,p2,tri,noise,dmc>] [--overclock <scanlines>] [--no-sprite-limit] [--dev-log] [--poke <addr=value>]... [--pause-at <frame>]... [--keys1 <keys>] [--keys2 <keys>] [--mic-key <key>] [--filter nearest|scanlines] [--palette ntsc|pal|<pal path>] [--gamma <n>] [--brightness <n>] [--saturation <n>] [--raw <load addr> [--reset <addr>] [--chr <chr path>]] [--video sdl|terminal] <file path>");
+            println!("       nes-emu report <file path>");
+            println!("       nes-emu bench-rom alu|memory|branch <out path>");
+            println!("       nes-emu bench <file path> [instructions]");
+            println!("       nes-emu fuzz <file path> [seed] [frames]");
+            println!("       nes-emu test-rom <file path>...");
+            println!("       nes-emu verify <file path> <movie> <expected ppm> --frame <n>");
+            std::process::exit(0);
+        }
+    };
+
+    // per-frame timing statistics (microseconds)
+    // the CPU gets what's left of the emulation time after the PPU and APU ticks
+    let mut perf_log = perf_log_path.map(|path| {
+        let mut log = BufWriter::new(File::create(path).unwrap());
+        writeln!(log, "frame,cpu_us,ppu_us,apu_us,render_us,present_us").unwrap();
+        log
+    });
+    let time_ticks = perf_log.is_some();
+    let tick_times = Rc::new(Cell::new(memory::TickTimes::default()));
+    let frame_tick_times = tick_times.clone();
+    let mut frame_count: u64 = 0;
+    let mut last_frame = Instant::now();
+
+    // SDL is only started for its window, the terminal backend draws to and reads keys from the terminal
+    let sdl_context = match video_backend.as_str() {
+        "sdl" => Some(sdl2::init().unwrap()),
+        "terminal" => None,
+        other => {
+            println!("unknown video backend: {}", other);
+            std::process::exit(1);
+        }
+    };
+
+    let creator;
+    let (mut video, mut host_events): (Box<dyn video::VideoSink>, Box<dyn input::EventSource<Keycode>>) = match sdl_context.as_ref() {
+        Some(sdl_context) => {
+            let video_subsys = sdl_context.video().unwrap();
+            let window = video_subsys
+                .window("test", 256 * 3, 240 * 3)
+                .position_centered()
+                .build().unwrap();
+            let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+            canvas.set_scale(3.0, 3.0).unwrap();
+            creator = canvas.texture_creator();
+            // a filter is applied at the window scale, SDL stretches plain frames itself
+            let video: Box<dyn video::VideoSink> = match filter {
+                Some(filter) => Box::new(video::sdl::SdlVideo::new(canvas, &creator, 3, filter)),
+                None => Box::new(video::sdl::SdlVideo::new(canvas, &creator, 1, render::scale::Filter::Nearest)),
+            };
+            (video, Box::new(video::sdl::SdlEvents::new(sdl_context.event_pump().unwrap())))
+        },
+        None => (
+            Box::new(video::terminal::TerminalVideo::new(std::io::stdout())),
+            Box::new(video::terminal::TerminalEvents::new()),
+        ),
+    };
+
+    let audio_spec = AudioSpecDesired {
+        freq: Some(apu::SAMPLE_RATE as i32),
+        channels: Some(if panning.is_some() { 2 } else { 1 }),
+        samples: Some(1024),
+    };
+    // audio goes through SDL, the terminal backend is silent
+    let audio_queue = sdl_context.as_ref().and_then(|sdl_context| {
+        match sdl_context.audio().and_then(|audio| audio.open_queue::<f32, _>(None, &audio_spec)) {
+            Ok(queue) => {
+                queue.resume();
+                Some(queue)
+            },
+            Err(msg) => {
+                println!("audio disabled: {}", msg);
+                None
+            }
+        }
+    });
+
     // open nes file
-    let path = Path::new(args[1].as_str());
-    let mut file = File::open(path).unwrap();
-    let mut raw: [u8; 0x20000] = [0; 0x20000];
-    file.read(&mut raw).unwrap();
-    let raw = raw.to_vec();
-    
+    let path = Path::new(rom_path.as_str());
+    let mut raw = std::fs::read(path).unwrap();
+    // a patch with the same name as the ROM is applied unless patches are given explicitly
+    if auto_patch && patch_paths.is_empty() {
+        let sibling = ["bps", "ips"].iter()
+            .map(|ext| path.with_extension(ext))
+            .find(|sibling| sibling.is_file());
+        if let Some(sibling) = sibling {
+            println!("applying {}", sibling.display());
+            patch_paths.push(sibling.to_string_lossy().into_owned());
+        }
+    }
+    for patch_path in patch_paths.iter() {
+        let patch = std::fs::read(patch_path).unwrap();
+        raw = match patch::apply(&raw, &patch) {
+            Ok(patched) => patched,
+            Err(msg) => {
+                println!("{}: {}", patch_path, msg);
+                std::process::exit(1);
+            }
+        };
+    }
+
     // load program
-    let rom = ines::Rom::analyze_raw(&raw).unwrap();
-    
-    for i in 0..=255 {
-        let x: i32 = i % 16;
-        let y: i32 = (i - x)/16;
-        let tile_frame = tile::show_tile(&rom.chr_rom, 1, i as usize);
-        texture.update(sdl2::rect::Rect::new(x*9, y*9, 8, 8), &tile_frame.data, 256 * 3).unwrap();
-    }
-    canvas.copy(&texture, None, None).unwrap();
-    canvas.present();
+    let rom = match raw_load {
+        Some(load_addr) => {
+            let chr = chr_path.map(|path| std::fs::read(path).unwrap());
+            let program = if load_addr >= 0x8000 { &raw[..] } else { &[] };
+            match ines::Rom::from_binary(program, load_addr, raw_reset.unwrap_or(load_addr), chr.as_ref().map(|chr| &chr[..])) {
+                Ok(rom) => rom,
+                Err(msg) => {
+                    println!("{}", msg);
+                    std::process::exit(1);
+                }
+            }
+        },
+        None => ines::Rom::analyze_raw(&raw).unwrap(),
+    };
+    if !cartridge::is_supported(rom.mapper) {
+        println!("mapper {} is not supported", rom.mapper);
+        std::process::exit(1);
+    }
+    // colors follow the ROM's region unless overridden
+    let base_palette = match palette_arg.as_deref() {
+        None => render::palette::generate(&rom.tv_system),
+        Some("ntsc") => render::palette::generate(&ines::TvSystem::Ntsc),
+        Some("pal") => render::palette::generate(&ines::TvSystem::Pal),
+        Some(path) => match std::fs::read(path).map_err(|e| e.to_string()).and_then(|data| render::palette::load(&data)) {
+            Ok(palette) => palette,
+            Err(msg) => {
+                println!("{}: {}", path, msg);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    video.set_title(&format!("nes-emu - {}", path.file_name().unwrap().to_string_lossy()));
+    // CHR RAM carts have nothing to show until the game uploads tiles
+    if !rom.chr_ram {
+        video.present(&tile::show_tile_bank(&rom.chr_rom, 1));
+    }
     
-    // setup the controller
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, controller::JoypadButton::DOWN);
-    key_map.insert(Keycode::Up, controller::JoypadButton::UP);
-    key_map.insert(Keycode::Right, controller::JoypadButton::RIGHT);
-    key_map.insert(Keycode::Left, controller::JoypadButton::LEFT);
-    key_map.insert(Keycode::Space, controller::JoypadButton::SELECT);
-    key_map.insert(Keycode::Return, controller::JoypadButton::START);
-    key_map.insert(Keycode::A, controller::JoypadButton::A);
-    key_map.insert(Keycode::S, controller::JoypadButton::B);
+    // setup the controllers
+    let mut bindings = vec![];
+    for (player, player_keys) in keys.iter().enumerate() {
+        for (key, button) in player_keys.iter().zip(KEY_ORDER.iter()) {
+            bindings.push((*key, input::Binding::Button(player, *button)));
+        }
+    }
+    for &(key, player, button) in TURBO_KEYS.iter() {
+        bindings.push((key, input::Binding::Turbo(player, button)));
+    }
+    bindings.push((mic_key, input::Binding::Microphone));
+    let mut input = input::Input::new(bindings);
+
+    // set by the frame callback, the CPU loop cleans up and exits
+    let quit = Rc::new(Cell::new(false));
+    let quit_requested = quit.clone();
 
     let mut frame = render::frame::Frame::new();
-    let bus = memory::Bus::new(rom, move |ppu: &ppu::Ppu| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+    frame.set_palette(color.table(&base_palette));
+    let mut previous_frame = render::LastFrame::new();
+    let mut paused = false;
+    let mut show_sources = false;
+    let mut bus = memory::Bus::new(rom, move |ppu: &ppu::Ppu, apu: &mut apu::Apu, joypads: &mut [controller::Joypad; 2]| {
+        let emulate_time = last_frame.elapsed();
+
+        // drop samples rather than let latency build up beyond 100ms
+        let samples = apu.take_samples();
+        if let Some(queue) = audio_queue.as_ref() {
+            let queued_samples = queue.size() as usize / std::mem::size_of::<f32>() / apu.channels() as usize;
+            if queued_samples < apu::SAMPLE_RATE as usize / 10 {
+                queue.queue(&samples);
+            }
+        }
+
+        let render_begin = Instant::now();
+        render::render_with(ppu, &mut previous_frame, &mut frame);
+        if show_sources {
+            render::show_sources(&previous_frame, &mut frame);
+        }
+        let render_time = render_begin.elapsed();
+
+        let present_begin = Instant::now();
+        video.present(&frame);
+        let present_time = present_begin.elapsed();
+
+        if let Some(log) = perf_log.as_mut() {
+            let ticks = frame_tick_times.take();
+            writeln!(log, "{},{},{},{},{},{}",
+                frame_count,
+                emulate_time.saturating_sub(ticks.ppu + ticks.apu).as_micros(),
+                ticks.ppu.as_micros(),
+                ticks.apu.as_micros(),
+                render_time.as_micros(),
+                present_time.as_micros()).unwrap();
+        }
+        frame_count += 1;
+
+        if pause_at.contains(&frame_count) {
+            println!("paused at frame {} (press P to resume)", frame_count);
+            paused = true;
+        }
+
+        // while paused, block on input instead of returning to the emulation
+        loop {
+            let events = if paused {
+                host_events.wait()
+            } else {
+                host_events.poll()
+            };
+            for event in events {
+                match event {
+                    input::HostEvent::Quit | input::HostEvent::KeyDown(Keycode::Escape) => {
+                        if let Some(log) = perf_log.as_mut() {
+                            log.flush().unwrap();
+                        }
+                        quit_requested.set(true);
+                    },
+                    input::HostEvent::KeyDown(Keycode::P) => {
+                        paused = !paused;
+                        if paused {
+                            println!("paused at frame {} (press P to resume)", frame_count);
+                        }
+                    },
+                    input::HostEvent::KeyDown(key) => input.key_down(key),
+                    input::HostEvent::KeyUp(key) => input.key_up(key),
+                }
+            }
+            if !paused || quit_requested.get() {
+                break;
+            }
+        }
+
+        let frame_input = input.next_frame();
+        for (joypad, &buttons) in joypads.iter_mut().zip(frame_input.buttons.iter()) {
+            joypad.set_button_status(controller::JoypadButton::all(), false);
+            joypad.set_button_status(buttons, true);
+        }
+        joypads[1].set_microphone(frame_input.microphone);
+        for hotkey in frame_input.hotkeys {
+            match hotkey.key {
+                Keycode::F11 if !hotkey.repeat => show_sources = !show_sources,
+                Keycode::F12 if !hotkey.repeat => devlog::write_summary(&mut std::io::stdout()).unwrap(),
+                key => if adjust_color(&mut color, key) {
+                    frame.set_palette(color.table(&base_palette));
+                    println!("gamma {:.1}, brightness {:.1}, saturation {:.1}",
+                        color.gamma, color.brightness, color.saturation);
+                },
+            }
+        }
+        last_frame = Instant::now();
+    });
+    let mut trace_out = trace_path.map(|path| BufWriter::new(File::create(path).unwrap()));
+    // reference trace to check every executed instruction against
+    let mut compare_log = compare_log_path.map(|path| {
+        BufReader::new(File::open(path).unwrap()).lines().enumerate()
+    });
+
+    if time_ticks {
+        bus.time_ticks(tick_times);
+    }
+    if heatmap_path.is_some() {
+        bus.enable_access_counter();
+    }
 
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. } | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => std::process::exit(0),
-                _ => (),
+    let mut livesplit = livesplit_addr.map(|addr| {
+        match livesplit::LiveSplit::connect(&addr) {
+            Ok(livesplit) => livesplit,
+            Err(e) => {
+                println!("cannot connect to the LiveSplit server at {}: {}", addr, e);
+                std::process::exit(1);
             }
         }
     });
+    if livesplit.is_none() && !splits.is_empty() {
+        println!("--split requires --livesplit");
+        std::process::exit(1);
+    }
+
+    bus.set_audio_panning(panning);
+    bus.set_overclock(overclock);
+    bus.set_sprite_limit(sprite_limit);
+
+    // battery-backed PRG RAM lives in game.sav next to game.nes
+    let save_path = path.with_extension("sav");
+    if bus.battery_ram().is_some() && save_path.is_file() {
+        bus.load_battery_ram(&std::fs::read(&save_path).unwrap());
+    }
+
+    for (addr, value) in pokes {
+        if let Err(msg) = bus.poke(addr, value) {
+            println!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+
     let mut cpu = cpu::Cpu::new(bus);
+    // binaries below $8000 go to RAM or PRG RAM
+    if let Some(load_addr) = raw_load.filter(|addr| *addr < 0x8000) {
+        if let Err(msg) = cpu.load(load_addr, &raw) {
+            println!("{}", msg);
+            std::process::exit(1);
+        }
+    }
     cpu.reset();
+    if opcode_stats_path.is_some() {
+        cpu.enable_opcode_counter();
+    }
+    // keys arrive one by one until this is dropped after the run loop
+    let raw_mode = match video_backend.as_str() {
+        "terminal" => match video::terminal::RawMode::enter() {
+            Ok(raw_mode) => Some(raw_mode),
+            Err(e) => {
+                println!("cannot read keys from the terminal: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    // set when the run loop stops on purpose, the process exits with it
+    let exit_code = Rc::new(Cell::new(None));
+    let exit_with = exit_code.clone();
+    let mut watched_frame = 0;
     // cpu.run();
     cpu.run_with_callback(move |cpu| {
+        let frame = cpu.bus.frame_count();
+        if frame != watched_frame {
+            watched_frame = frame;
+            for watch in watches.iter_mut() {
+                let value = cpu.bus.peek(watch.condition.addr());
+                if watch.update(value) {
+                    println!("frame {}: {} (value: {})", frame, watch.expr, value);
+                }
+            }
+            for split in splits.iter_mut() {
+                let value = cpu.bus.peek(split.condition.addr());
+                if !split.update(value) {
+                    continue;
+                }
+                // keep playing when LiveSplit goes away, without splits
+                if let Err(e) = livesplit.as_mut().map_or(Ok(()), |server| server.start_or_split()) {
+                    println!("frame {}: lost the LiveSplit server ({}), splits are disabled", frame, e);
+                    livesplit = None;
+                }
+            }
+        }
+        if quit.get() {
+            if let Some(out) = trace_out.as_mut() {
+                out.flush().unwrap();
+            }
+            if let Some(path) = heatmap_path.as_ref() {
+                let mut out = BufWriter::new(File::create(path).unwrap());
+                let ram = cpu.bus.ram_access.as_ref().unwrap();
+                let vram = cpu.bus.vram_access().unwrap();
+                heatmap::write_ppm(&mut out, &[ram, vram]).unwrap();
+            }
+            if let Some(path) = opcode_stats_path.as_ref() {
+                let mut out = BufWriter::new(File::create(path).unwrap());
+                opstats::write_report(&mut out, cpu.opcode_counts.as_ref().unwrap()).unwrap();
+            }
+            if let Some(data) = cpu.bus.battery_ram() {
+                if let Err(e) = write_atomically(&save_path, data) {
+                    println!("cannot write {}: {}", save_path.display(), e);
+                }
+            }
+            if dev_log {
+                devlog::write_summary(&mut std::io::stdout()).unwrap();
+            }
+            exit_with.set(Some(0));
+            cpu.halt();
+            return;
+        }
+        if let Some(out) = trace_out.as_mut() {
+            let line = if trace_json { trace::trace_json(cpu) } else { trace::trace(cpu) };
+            writeln!(out, "{}", line).unwrap();
+        }
+        if let Some(log) = compare_log.as_mut() {
+            // registers are compared straight from the CPU, the trace line is only for the report
+            match log.next() {
+                Some((line_no, theirs)) => {
+                    let theirs = theirs.unwrap();
+                    let same = trace::TraceState::parse(&theirs)
+                        .is_some_and(|b| trace::TraceState::of(cpu).matches(&b));
+                    if !same {
+                        println!("diverged at line {}", line_no + 1);
+                        println!("expected: {}", theirs);
+                        println!("actual:   {}", trace::trace(cpu));
+                        exit_with.set(Some(1));
+                        cpu.halt();
+                    }
+                },
+                None => {
+                    println!("reached the end of the log without divergence");
+                    exit_with.set(Some(0));
+                    cpu.halt();
+                }
+            }
+        }
         //let opcode = cpu.mem_read(cpu.pc);
         //println!("{:X}", opcode);
         //println!("{}", trace::trace(cpu));
     });
+    // exit skips destructors, the terminal settings are put back first
+    drop(raw_mode);
+    if let Some(code) = exit_code.get() {
+        std::process::exit(code);
+    }
+    // nothing else halts the CPU but a KIL/JAM opcode
+    if cpu.is_jammed() {
+        println!("CPU jammed at ${:04X}", cpu.pc);
+        std::process::exit(1);
+    }
 }
@@ -1,9 +1,21 @@
 use ines::Rom;
-use ppu::Ppu;
+use ppu::{Ppu, PpuDevice};
+use apu::{Apu, ApuDevice, Panning};
+use heatmap::AccessCounter;
+use controller::Joypad;
+use cartridge::{Cartridge, Mapper};
+use devlog;
+use state;
+use std::io::{self, Write};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 const RAM: u16 = 0x0000;
 const RAM_MIRROR_END: u16 = 0x1fff;
 const PPU_REGISTERS_MIRROR_END: u16 = 0x3fff;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 const PRG_ROM: u16 = 0x8000;
 const PRG_ROM_END: u16 = 0xFFFF;
 
@@ -35,37 +47,162 @@ const PRG_ROM_END: u16 = 0xFFFF;
 // | Zero Page     |       |               |
 // |_______________| $0000 |_______________|
 
+// time spent stepping the PPU and APU, for the perf log
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TickTimes {
+    pub ppu: Duration,
+    pub apu: Duration,
+}
+
+// called once per frame with the PPU, the APU and the joypads
+pub type GameloopCallback<'call> = Box<dyn FnMut(&Ppu, &mut Apu, &mut [Joypad; 2]) + 'call>;
+
 pub struct Bus<'call> {
     // 0x800 = 2048
     cpu_vram: [u8; 0x800],
-    prg_rom: Vec<u8>,
-    ppu: Ppu,
+    // work RAM at $6000-$7FFF, saved to disk when battery-backed
+    prg_ram: Vec<u8>,
+    battery: bool,
+    cartridge: Cartridge,
+    ppu: Box<dyn PpuDevice>,
+    apu: Box<dyn ApuDevice>,
+    joypads: [Joypad; 2],
     cycles: usize,
-    gameloop_callback: Box<FnMut(&Ppu) + 'call>,
+    frames: usize,
+    // IRQ line, asserted while any source (APU, mapper) holds it
+    pending_irq: bool,
+    // last value on the CPU data bus, unmapped addresses read it back
+    open_bus: u8,
+    // CPU cycles stolen by DMA, not yet accounted for
+    dma_stall: u8,
+    pub ram_access: Option<AccessCounter>,
+    // added to on every tick when set, the owner takes it e.g. once per frame
+    tick_times: Option<Rc<Cell<TickTimes>>>,
+    gameloop_callback: GameloopCallback<'call>,
 }
 
-impl<'a> Bus<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
-    where F: FnMut(&Ppu) + 'call
+// assembles a Bus from parts, anything not given gets its default
+// a mapper, PPU or APU can be passed directly, e.g. a stub that records accesses in tests
+pub struct BusBuilder<'call> {
+    rom: Option<Rom>,
+    mapper: Option<Box<dyn Mapper>>,
+    ppu: Option<Box<dyn PpuDevice>>,
+    apu: Option<Box<dyn ApuDevice>>,
+    gameloop_callback: Option<GameloopCallback<'call>>,
+}
+
+impl<'call> BusBuilder<'call> {
+    pub fn new() -> Self {
+        BusBuilder {
+            rom: None,
+            mapper: None,
+            ppu: None,
+            apu: None,
+            gameloop_callback: None,
+        }
+    }
+
+    pub fn rom(mut self, rom: Rom) -> Self {
+        self.rom = Some(rom);
+        self
+    }
+
+    // used instead of the mapper the ROM would create
+    pub fn mapper(mut self, mapper: Box<dyn Mapper>) -> Self {
+        self.mapper = Some(mapper);
+        self
+    }
+
+    // used instead of a Ppu on the cartridge, the frame callback only runs with a real Ppu
+    pub fn ppu(mut self, ppu: Box<dyn PpuDevice>) -> Self {
+        self.ppu = Some(ppu);
+        self
+    }
+
+    // the frame callback only runs with a real Apu
+    pub fn apu(mut self, apu: Box<dyn ApuDevice>) -> Self {
+        self.apu = Some(apu);
+        self
+    }
+
+    pub fn callback<F>(mut self, gameloop_callback: F) -> Self
+    where F: FnMut(&Ppu, &mut Apu, &mut [Joypad; 2]) + 'call
     {
-        let ppu = Ppu::new(rom.chr_rom, rom.mirroring);
-        Bus {
+        self.gameloop_callback = Some(Box::new(gameloop_callback));
+        self
+    }
+
+    pub fn build(self) -> Result<Bus<'call>, String> {
+        let battery = self.rom.as_ref().is_some_and(|rom| rom.battery);
+        let mapper = match (self.mapper, self.rom) {
+            (Some(mapper), _) => mapper,
+            (None, Some(rom)) => rom.into_mapper()?,
+            (None, None) => return Err(String::from("a ROM or a mapper is required")),
+        };
+        let cartridge: Cartridge = Rc::new(RefCell::new(mapper));
+        let ppu = self.ppu.unwrap_or_else(|| Box::new(Ppu::new(cartridge.clone())));
+        Ok(Bus {
             cpu_vram: [0; 0x800],
-            prg_rom: rom.prg_rom,
-            ppu: ppu,
+            prg_ram: vec![0; 0x2000],
+            battery,
+            cartridge,
+            ppu,
+            apu: self.apu.unwrap_or_else(|| Box::new(Apu::new())),
+            joypads: [Joypad::new(), Joypad::new()],
             cycles: 0,
-            gameloop_callback: Box::from(gameloop_callback),
-        }
+            frames: 0,
+            pending_irq: false,
+            open_bus: 0,
+            dma_stall: 0,
+            ram_access: None,
+            tick_times: None,
+            gameloop_callback: self.gameloop_callback.unwrap_or_else(|| Box::new(|_: &Ppu, _: &mut Apu, _: &mut [Joypad; 2]| {})),
+        })
+    }
+}
+
+impl<'a> Bus<'a> {
+    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
+    where F: FnMut(&Ppu, &mut Apu, &mut [Joypad; 2]) + 'call
+    {
+        BusBuilder::new().rom(rom).callback(gameloop_callback).build().unwrap()
+    }
+
+    // CPU cycles since power on
+    pub fn cycles(&self) -> usize {
+        self.cycles
     }
 
-    // TODO: FIX ME!
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
         // let prev_nmi = self.ppu.nmi_interrupt.is_some();
+        let timed = self.tick_times.is_some();
+        let ppu_begin = timed.then(Instant::now);
         // PPU clock is 3 times faster than CPU clock
         let new_frame = self.ppu.tick(cycles * 3);
+        let apu_begin = timed.then(Instant::now);
+        // keep audio (and DMC fetches) at normal speed during overclocked scanlines
+        if !self.ppu.as_ppu().is_some_and(|ppu| ppu.is_overclocking()) {
+            self.apu.tick(cycles);
+        }
+        if let (Some(times), Some(ppu_begin), Some(apu_begin)) = (self.tick_times.as_ref(), ppu_begin, apu_begin) {
+            let mut t = times.get();
+            t.ppu += apu_begin - ppu_begin;
+            t.apu += apu_begin.elapsed();
+            times.set(t);
+        }
+        if let Some(addr) = self.apu.dmc_dma_request() {
+            let data = self.mem_read(addr);
+            self.apu.dmc_dma_complete(data);
+            // the CPU is halted while the DMC fetches a sample byte
+            self.dma_stall += 4;
+        }
+        self.pending_irq = self.apu.irq() || self.cartridge.borrow().irq();
         if new_frame {
-            (self.gameloop_callback)(&self.ppu);
+            self.frames += 1;
+            if let (Some(ppu), Some(apu)) = (self.ppu.as_ppu(), self.apu.as_apu_mut()) {
+                (self.gameloop_callback)(ppu, apu, &mut self.joypads);
+            }
         }
         // let cur_nmi = self.ppu.nmi_interrupt.is_some();
         // if !prev_nmi && cur_nmi {
@@ -74,9 +211,124 @@ impl<'a> Bus<'a> {
         //}
     }
 
+    pub fn take_dma_stall(&mut self) -> u8 {
+        std::mem::take(&mut self.dma_stall)
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.nmi_interrupt.take()
+        self.ppu.poll_nmi()
     } 
+
+    // IRQ is level triggered, the source keeps it asserted until acknowledged
+    pub fn poll_irq_status(&self) -> bool {
+        self.pending_irq
+    }
+
+    // change RAM or PRG RAM from outside the emulation
+    // unlike mem_write, never touches registers and isn't counted by the heatmap
+    pub fn poke(&mut self, addr: u16, data: u8) -> Result<(), String> {
+        match addr {
+            RAM ..= RAM_MIRROR_END => {
+                self.cpu_vram[(addr & 0b00000111_11111111) as usize] = data;
+                Ok(())
+            },
+            PRG_RAM ..= PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
+                Ok(())
+            },
+            _ => Err(format!("cannot poke 0x{:X}, only RAM and PRG RAM", addr)),
+        }
+    }
+
+    // what mem_read would return, for tools like the tracer
+    // unlike mem_read, leaves registers, the open bus and the heatmap alone
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM ..= RAM_MIRROR_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            0x2000 ..= 0x2007 => self.ppu.peek_register(addr),
+            0x2008 ..= PPU_REGISTERS_MIRROR_END => self.peek(addr & 0b00100000_00000111),
+            0x4015 => self.apu.peek_status() & 0b1101_1111 | self.open_bus & 0b0010_0000,
+            0x4016 => self.joypads[0].peek() | self.joypads[1].microphone_bit() | self.open_bus & 0b1110_0000,
+            0x4017 => self.joypads[1].peek() | self.open_bus & 0b1110_0000,
+            PRG_RAM ..= PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            PRG_ROM ..= PRG_ROM_END => self.read_prg_rom(addr),
+            _ => self.open_bus,
+        }
+    }
+
+    pub fn peek_u16(&self, addr: u16) -> u16 {
+        (self.peek(addr.wrapping_add(1)) as u16) << 8 | self.peek(addr) as u16
+    }
+
+    // contents of battery-backed PRG RAM, None if the cartridge has no battery
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    // extra scanlines per frame for the CPU, 0 to disable
+    pub fn set_overclock(&mut self, scanlines: u16) {
+        if let Some(ppu) = self.ppu.as_ppu_mut() {
+            ppu.extra_scanlines = scanlines;
+        }
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        if let Some(ppu) = self.ppu.as_ppu_mut() {
+            ppu.sprite_limit = enabled;
+        }
+    }
+
+    pub fn set_audio_panning(&mut self, panning: Option<Panning>) {
+        if let Some(apu) = self.apu.as_apu_mut() {
+            apu.set_panning(panning);
+        }
+    }
+
+    // number of frames completed so far
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    // (scanline, dot) of the PPU
+    pub fn ppu_position(&self) -> (u16, usize) {
+        self.ppu.position()
+    }
+
+    // start adding up the time spent in the PPU and APU
+    pub fn time_ticks(&mut self, times: Rc<Cell<TickTimes>>) {
+        self.tick_times = Some(times);
+    }
+
+    // start counting accesses to CPU RAM and PPU VRAM
+    pub fn enable_access_counter(&mut self) {
+        self.ram_access = Some(AccessCounter::new(0x800));
+        if let Some(ppu) = self.ppu.as_ppu_mut() {
+            ppu.vram_access = Some(AccessCounter::new(0x800));
+        }
+    }
+
+    // bus counters, the PPU, then CPU RAM and PRG RAM as hex
+    pub fn write_state<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "bus: cycles:{} frames:{} irq:{} open bus:{:02X}", self.cycles, self.frames, self.pending_irq, self.open_bus)?;
+        if let Some(ppu) = self.ppu.as_ppu() {
+            ppu.write_state(out)?;
+        }
+        state::write_hex(out, "ram", RAM, &self.cpu_vram)?;
+        state::write_hex(out, "prg ram", PRG_RAM, &self.prg_ram)
+    }
+
+    pub fn vram_access(&self) -> Option<&AccessCounter> {
+        self.ppu.as_ppu().and_then(|ppu| ppu.vram_access.as_ref())
+    }
 }
 
 pub trait Mem {
@@ -89,43 +341,41 @@ pub trait Mem {
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let data = match addr {
             // 0x0000 ~ 0x1fff used as RAM
             RAM ..= RAM_MIRROR_END => {
                 let lower_11_bits = addr & 0b00000111_11111111;
+                if let Some(counter) = self.ram_access.as_mut() {
+                    counter.read(lower_11_bits as usize);
+                }
                 self.cpu_vram[lower_11_bits as usize]
             },
+            0x2000 ..= 0x2007 => self.ppu.read_register(addr),
             // write only
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                // TODO: need to be panic?
-                // panic!("read from write only memory");
-                0
-            },
-            0x2002 => self.ppu.read_status(),
-            0x2004 => self.ppu.read_oam_data(),
-            0x2007 => self.ppu.read_data(), 
+            0x4014 => self.open_bus,
             0x2008 ..= PPU_REGISTERS_MIRROR_END => {
                 let mirrored = addr & 0b00100000_00000111;
                 self.mem_read(mirrored)
             },
-            0x4000 ..= 0x4015 => {
-                // TODO: ignore APU
-                0
-            },
-            0x4016 => {
-                // TODO: ignore joypad 1
-                0
-            },
-            0x4017 => {
-                // TODO: ignore joypad 2
-                0
+            // bit 5 is not driven
+            0x4015 => self.apu.read_status() & 0b1101_1111 | self.open_bus & 0b0010_0000,
+            0x4000 ..= 0x4013 => {
+                // APU registers are write only
+                self.open_bus
             },
+            // the controller ports only drive the low bits
+            0x4016 => self.joypads[0].read() | self.joypads[1].microphone_bit() | self.open_bus & 0b1110_0000,
+            0x4017 => self.joypads[1].read() | self.open_bus & 0b1110_0000,
+            PRG_RAM ..= PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
             PRG_ROM ..= PRG_ROM_END => self.read_prg_rom(addr),
+            // nothing answers, the value left on the data bus is read
             _ => {
-                print!("ignored memory read-acess to 0x{:X}", addr);
-                0
+                devlog::hit("unmapped read");
+                self.open_bus
             },
-        }
+        };
+        self.open_bus = data;
+        data
     }
     
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
@@ -135,48 +385,39 @@ impl Mem for Bus<'_> {
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
         match addr {
             // 0x0000 ~ 0x1fff used as RAM
             RAM ..= RAM_MIRROR_END => {
                 let lower_11_bits = addr & 0b00000111_11111111;
+                if let Some(counter) = self.ram_access.as_mut() {
+                    counter.write(lower_11_bits as usize);
+                }
                 self.cpu_vram[lower_11_bits as usize] = data;
             },
-            0x2000 => {
-                self.ppu.write_to_ctrl(data);
-            },
-            0x2001 => {
-                self.ppu.write_to_mask(data);
-            },
-            0x2002 => panic!("Cannot write to PPU status register"),
-            0x2003 => {
-                self.ppu.write_to_oam_addr(data);
-            },
-            0x2004 => {
-                self.ppu.write_to_oam_data(data);
-            },
-            0x2005 => {
-                self.ppu.write_to_scroll(data);
-            },
-            0x2006 => {
-                self.ppu.write_to_ppu_addr(data);
-            },
-            0x2007 => {
-                self.ppu.write_to_data(data);
+            0x2000 ..= 0x2007 => {
+                self.ppu.write_register(addr, data);
             },
             0x2008 ..= PPU_REGISTERS_MIRROR_END => {
                 let mirrored = addr & 0b00100000_00000111;
                 self.mem_write(mirrored, data);
             },
             0x4000 ..= 0x4013 | 0x4015 => {
-                // TODO: ignore APU
+                self.apu.write_register(addr, data);
             },
             0x4016 => {
-                // TODO: ignore joypad 1
+                // the strobe line is shared by both ports
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write(data);
+                }
             },
             0x4017 => {
-                // TODO: ignore joypad 2
+                // APU frame counter
+                self.apu.write_register(addr, data);
             },
             0x4014 => {
+                // the CPU isn't stalled for the 513 cycles of the copy
+                devlog::hit("OAM DMA ($4014) without CPU stall");
                 let mut buf: [u8; 256] = [0; 256];
                 let hi: u16 = (data as u16) << 8;
                 for i in 0 .. 256u16 {
@@ -184,11 +425,11 @@ impl Mem for Bus<'_> {
                 }
                 self.ppu.write_oam_dma(&buf);
             },
-            0x8000 ..=0xffff => panic!("cannot write to program ROM: 0x{:X}", addr),
-            _ => {
-                print!("ignored memory write-access to 0x{:X}", addr);
-                panic!();
+            PRG_RAM ..= PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
             },
+            PRG_ROM ..= PRG_ROM_END => self.cartridge.borrow_mut().prg_write(addr, data),
+            _ => devlog::hit("unmapped write"),
         }
     }
 
@@ -199,11 +440,122 @@ impl Mem for Bus<'_> {
         self.mem_write(pos + 1, high);
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= PRG_ROM;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr %= 0x4000;
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        self.cartridge.borrow().prg_read(addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ppu::Mirroring;
+    use ines::test;
+    use controller;
+
+    #[derive(Debug)]
+    struct RecordingMapper {
+        writes: Rc<RefCell<Vec<(u16, u8)>>>,
+    }
+
+    impl Mapper for RecordingMapper {
+        fn prg_read(&self, addr: u16) -> u8 {
+            (addr >> 8) as u8
+        }
+        fn prg_write(&mut self, addr: u16, data: u8) {
+            self.writes.borrow_mut().push((addr, data));
         }
-        self.prg_rom[addr as usize]
+        fn chr_read(&self, _addr: u16) -> u8 {
+            0
+        }
+        fn chr_write(&mut self, _addr: u16, _data: u8) {}
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Horizontal
+        }
+    }
+
+    #[test]
+    fn test_builder_with_mapper() {
+        let writes = Rc::new(RefCell::new(vec![]));
+        let mut bus = BusBuilder::new()
+            .mapper(Box::new(RecordingMapper { writes: writes.clone() }))
+            .build()
+            .unwrap();
+        assert_eq!(bus.mem_read(0xc123), 0xc1);
+        bus.mem_write(0x8000, 0x12);
+        bus.mem_write(0x6000, 0x34);
+        assert_eq!(*writes.borrow(), vec![(0x8000, 0x12)]);
+        assert_eq!(bus.mem_read(0x6000), 0x34);
+
+        assert!(BusBuilder::new().build().is_err());
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        bus.mem_write(0x10, 0x5a);
+        assert_eq!(bus.mem_read(0x10), 0x5a);
+        assert_eq!(bus.mem_read(0x5000), 0x5a);
+        assert_eq!(bus.mem_read(0x4000), 0x5a);
+        // reads drive the bus too
+        bus.mem_write(0x11, 0x40);
+        bus.mem_read(0x11);
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        bus.mem_write(0x10, 0x5a);
+        bus.mem_write(0x6000, 0x34);
+        bus.mem_write(0x20, 0x77);
+        assert_eq!(bus.peek(0x810), 0x5a);
+        assert_eq!(bus.peek_u16(0x6000), 0x0034);
+        // doesn't drive the open bus
+        assert_eq!(bus.peek(0x5000), 0x77);
+        assert_eq!(bus.mem_read(0x10), 0x5a);
+        assert_eq!(bus.peek(0x5000), 0x5a);
+
+        // doesn't shift the joypads
+        bus.joypads[0].set_button_status(controller::JoypadButton::A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.peek(0x4016) & 1, 1);
+        assert_eq!(bus.peek(0x4016) & 1, 1);
+        assert_eq!(bus.mem_read(0x4016) & 1, 1);
+        assert_eq!(bus.peek(0x4016) & 1, 0);
+
+        // doesn't clear vblank
+        while bus.ppu_position().0 != 241 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.peek(0x3002) & 0x80, 0x80);
+        assert_eq!(bus.mem_read(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.peek(0x2002) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_tick_times() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let times = Rc::new(Cell::new(TickTimes::default()));
+        bus.time_ticks(times.clone());
+        while bus.frame_count() == 0 {
+            bus.tick(1);
+        }
+        let t = times.take();
+        assert!(t.ppu > Duration::ZERO && t.apu > Duration::ZERO);
+        assert_eq!(times.get().ppu, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_microphone() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        bus.joypads[1].set_microphone(true);
+        assert_eq!(bus.mem_read(0x4016) & 0b111, 0b100);
+        // it doesn't shift out with the buttons of either joypad
+        assert_eq!(bus.mem_read(0x4016) & 0b111, 0b100);
+        assert_eq!(bus.mem_read(0x4017) & 0b111, 0);
+        bus.joypads[1].set_microphone(false);
+        assert_eq!(bus.mem_read(0x4016) & 0b111, 0);
     }
 }
@@ -0,0 +1,41 @@
+use std::io::{Result, Write};
+use instructions;
+
+// executed opcodes, most frequent first
+// unofficial opcodes are marked with * like in the trace
+pub fn write_report<W: Write>(out: &mut W, counts: &[u64]) -> Result<()> {
+    let total: u64 = counts.iter().sum();
+    let mut used: Vec<(usize, u64)> = counts.iter().cloned().enumerate().filter(|(_, n)| *n > 0).collect();
+    used.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    writeln!(out, "opcode,mnemonic,mode,count,percent")?;
+    for (opcode, count) in used {
+        let (mnemonic, mode) = match instructions::INSTRUCTION_TABLE[opcode] {
+            Some(inst) => (inst.mnemonic, format!("{:?}", inst.mode)),
+            None => ("???", String::new()),
+        };
+        writeln!(out, "{:02X},{},{},{},{:.3}", opcode, mnemonic, mode, count, count as f64 * 100.0 / total as f64)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut counts = vec![0; 256];
+        counts[0xa9] = 3;
+        counts[0xe8] = 1;
+        counts[0xa7] = 4;
+        let mut out = vec![];
+        write_report(&mut out, &counts).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], "A7,*LAX,ZeroPage,4,50.000");
+        assert_eq!(lines[2], "A9,LDA,Immediate,3,37.500");
+        assert_eq!(lines[3], "E8,INX,Implied,1,12.500");
+    }
+}
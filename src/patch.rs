@@ -0,0 +1,238 @@
+// soft patching of ROM images, applied to the whole file (header included)
+// IPS: https://zerosoft.zophar.net/ips.php
+// BPS: https://www.romhacking.net/documents/746/
+
+pub fn apply(raw: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(raw, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(raw, patch)
+    } else {
+        Err(String::from("unknown patch format"))
+    }
+}
+
+fn read_be(patch: &[u8], pos: usize, len: usize) -> Result<usize, String> {
+    if pos + len > patch.len() {
+        return Err(String::from("IPS patch is truncated"));
+    }
+    Ok(patch[pos..pos + len].iter().fold(0, |acc, b| acc << 8 | *b as usize))
+}
+
+fn apply_ips(raw: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = raw.to_vec();
+    let mut pos = 5;
+    loop {
+        if patch.get(pos..pos + 3) == Some(b"EOF") {
+            pos += 3;
+            break;
+        }
+        let offset = read_be(patch, pos, 3)?;
+        let size = read_be(patch, pos + 3, 2)?;
+        pos += 5;
+        // size 0 is a run of a single byte
+        let data = if size == 0 {
+            let count = read_be(patch, pos, 2)?;
+            let value = read_be(patch, pos + 2, 1)? as u8;
+            pos += 3;
+            vec![value; count]
+        } else {
+            if pos + size > patch.len() {
+                return Err(String::from("IPS patch is truncated"));
+            }
+            pos += size;
+            patch[pos - size..pos].to_vec()
+        };
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    // optional truncation extension
+    if pos + 3 <= patch.len() {
+        let len = read_be(patch, pos, 3)?;
+        out.truncate(len);
+    }
+    Ok(out)
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+struct BpsReader<'a> {
+    patch: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BpsReader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.patch.get(self.pos).ok_or_else(|| String::from("BPS patch is truncated"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let mut data = 0;
+        let mut shift = 1;
+        loop {
+            let x = self.byte()? as usize;
+            data += (x & 0x7f) * shift;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift <<= 7;
+            data += shift;
+        }
+    }
+
+    // relative offsets are stored as magnitude << 1 | sign
+    fn offset(&mut self) -> Result<isize, String> {
+        let n = self.number()?;
+        let magnitude = (n >> 1) as isize;
+        Ok(if n & 1 != 0 { -magnitude } else { magnitude })
+    }
+}
+
+fn checksum_at(patch: &[u8], pos: usize) -> u32 {
+    patch[pos..pos + 4].iter().rev().fold(0, |acc, b| acc << 8 | *b as u32)
+}
+
+fn apply_bps(raw: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err(String::from("BPS patch is truncated"));
+    }
+    let footer = patch.len() - 12;
+    if crc32(&patch[..footer + 8]) != checksum_at(patch, footer + 8) {
+        return Err(String::from("BPS patch is corrupted"));
+    }
+    if crc32(raw) != checksum_at(patch, footer) {
+        return Err(String::from("BPS patch is for a different ROM"));
+    }
+
+    let mut reader = BpsReader { patch: &patch[..footer], pos: 4 };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.pos += metadata_size;
+    if source_size != raw.len() {
+        return Err(String::from("BPS patch is for a different ROM"));
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    let bad_offset = || String::from("BPS patch reads out of range");
+    while reader.pos < footer {
+        let data = reader.number()?;
+        let length = (data >> 2) + 1;
+        match data & 0b11 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                let src = raw.get(start..start + length).ok_or_else(bad_offset)?;
+                out.extend_from_slice(src);
+            },
+            // TargetRead
+            1 => {
+                for _ in 0..length {
+                    let b = reader.byte()?;
+                    out.push(b);
+                }
+            },
+            // SourceCopy
+            2 => {
+                source_offset += reader.offset()?;
+                let start = source_offset as usize;
+                if source_offset < 0 {
+                    return Err(bad_offset());
+                }
+                let src = raw.get(start..start + length).ok_or_else(bad_offset)?;
+                out.extend_from_slice(src);
+                source_offset += length as isize;
+            },
+            // TargetCopy, may overlap the bytes being written
+            _ => {
+                target_offset += reader.offset()?;
+                for _ in 0..length {
+                    if target_offset < 0 || target_offset as usize >= out.len() {
+                        return Err(bad_offset());
+                    }
+                    let b = out[target_offset as usize];
+                    out.push(b);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+
+    if out.len() != target_size || crc32(&out) != checksum_at(patch, footer + 4) {
+        return Err(String::from("BPS patch produced a wrong result"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_ips() {
+        let raw = vec![0; 8];
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 1
+        patch.extend(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+        // run of 3 bytes at 5
+        patch.extend(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, 0xcc]);
+        patch.extend(b"EOF");
+        assert_eq!(apply(&raw, &patch).unwrap(), vec![0, 0xaa, 0xbb, 0, 0, 0xcc, 0xcc, 0xcc]);
+
+        // truncated to 4 bytes
+        patch.extend(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply(&raw, &patch).unwrap(), vec![0, 0xaa, 0xbb, 0]);
+    }
+
+    fn bps_patch(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        // sizes below 0x80 encode as a single byte
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(&[0x80 | source.len() as u8, 0x80 | target.len() as u8, 0x80]);
+        patch.extend(actions);
+        patch.extend(&crc32(source).to_le_bytes());
+        patch.extend(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 2, 9, 9, 9, 3, 4];
+        let actions = [
+            0x80 | (1 << 2),        // SourceRead 2
+            0x80 | 1,               // TargetRead 1
+            9,
+            0x80 | (1 << 2) | 3,    // TargetCopy 2 from out[2]
+            0x80 | (2 << 1),
+            0x80 | (1 << 2) | 2,    // SourceCopy 2 from source[2]
+            0x80 | (2 << 1),
+        ];
+        let patch = bps_patch(&source, &target, &actions);
+        assert_eq!(apply(&source, &patch).unwrap(), target.to_vec());
+
+        // wrong source
+        assert!(apply(&[1, 2, 3, 5], &patch).is_err());
+    }
+}
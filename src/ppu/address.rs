@@ -1,50 +1,157 @@
+// the PPU's internal scroll and address registers
+// https://www.nesdev.org/wiki/PPU_scrolling
+// v (current) and t (temporary) are laid out as yyy NN YYYYY XXXXX:
+// fine Y, nametable, coarse Y and coarse X. x is the fine X scroll,
+// w the write toggle shared by $2005 and $2006
 #[derive(Debug)]
 pub struct AddrRegister {
-    value: (u8, u8),
-    hi_ptr: bool,
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    w: bool,
 }
 
 impl AddrRegister {
     pub fn new() -> Self {
         AddrRegister {
-            value: (0, 0),
-            hi_ptr: true,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
         }
     }
 
-    fn set(&mut self, data: u16) {
-        self.value.0 = (data >> 8) as u8;
-        self.value.1 = (data & 0xff) as u8;
+    // PPUDATA address
+    pub fn get(&self) -> u16 {
+        self.v & 0x3fff
     }
 
-    pub fn get(&self) -> u16 {
-        ((self.value.0 as u16) << 8) | (self.value.1 as u16)
+    // $2000 bits 0-1
+    pub fn set_nametable(&mut self, data: u8) {
+        self.t = self.t & !0x0c00 | ((data & 0b11) as u16) << 10;
     }
 
-    pub fn update(&mut self, data: u8) {
-        if self.hi_ptr {
-            self.value.0 = data;
+    // $2005: X, then Y
+    pub fn write_scroll(&mut self, data: u8) {
+        if self.w {
+            self.t = self.t & !0x73e0 | ((data & 0b111) as u16) << 12 | ((data >> 3) as u16) << 5;
         } else {
-            self.value.1 = data;
+            self.t = self.t & !0x001f | (data >> 3) as u16;
+            self.x = data & 0b111;
         }
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b11111111111111);
+        self.w = !self.w;
+    }
+
+    // $2006: high byte, then low byte, which also copies t to v
+    pub fn update(&mut self, data: u8) {
+        if self.w {
+            self.t = self.t & 0xff00 | data as u16;
+            self.v = self.t;
+        } else {
+            self.t = self.t & 0x00ff | ((data & 0x3f) as u16) << 8;
         }
-        self.hi_ptr = !self.hi_ptr;
+        self.w = !self.w;
     }
 
     pub fn inc(&mut self, by: u8) {
-        let low = self.value.1;
-        self.value.1 = self.value.1.wrapping_add(by);
-        if low > self.value.1 {
-            self.value.0 = self.value.0.wrapping_add(1);
+        self.v = self.v.wrapping_add(by as u16) & 0x7fff;
+    }
+
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    // next tile to the right, into the neighbouring nametable after column 31
+    pub fn increment_x(&mut self) {
+        if self.v & 0x001f == 31 {
+            self.v = self.v & !0x001f ^ 0x0400;
+        } else {
+            self.v += 1;
         }
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b11111111111111);
+    }
+
+    // next pixel row, into the nametable below after row 29
+    // rows 30 and 31 hold attributes and wrap within the nametable
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
         }
+        self.v &= !0x7000;
+        let coarse_y = match (self.v & 0x03e0) >> 5 {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            },
+            31 => 0,
+            y => y + 1,
+        };
+        self.v = self.v & !0x03e0 | coarse_y << 5;
     }
 
-    pub fn reset_latch(&mut self) {
-        self.hi_ptr = true;
+    // horizontal scroll and nametable from t, at the end of each scanline
+    pub fn copy_x(&mut self) {
+        self.v = self.v & !0x041f | self.t & 0x041f;
     }
-}
\ No newline at end of file
+
+    // vertical scroll and nametable from t, on the pre-render scanline
+    pub fn copy_y(&mut self) {
+        self.v = self.v & !0x7be0 | self.t & 0x7be0;
+    }
+
+    // nametable byte of the tile at v
+    pub fn tile_addr(&self) -> u16 {
+        0x2000 | self.v & 0x0fff
+    }
+
+    // attribute byte covering the tile at v
+    pub fn attribute_addr(&self) -> u16 {
+        0x23c0 | self.v & 0x0c00 | (self.v >> 4) & 0x38 | (self.v >> 2) & 0x07
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        self.v >> 12 & 0b111
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scroll_writes() {
+        let mut addr = AddrRegister::new();
+        addr.set_nametable(0b10);
+        // X = 125, Y = 94
+        addr.write_scroll(0b0111_1101);
+        addr.write_scroll(0b0101_1110);
+        assert_eq!(addr.t, 0b110 << 12 | 0b10 << 10 | 11 << 5 | 15);
+        assert_eq!(addr.x, 0b101);
+        // $2006 shares the toggle, the second write copies t to v
+        addr.update(0x3d);
+        addr.update(0xf0);
+        assert_eq!((addr.t, addr.v), (0x3df0, 0x3df0));
+    }
+
+    #[test]
+    fn test_increments() {
+        let mut addr = AddrRegister::new();
+        addr.v = 31;
+        addr.increment_x();
+        assert_eq!(addr.v, 0x0400);
+
+        // the last pixel row of tile row 29 moves to the nametable below
+        addr.v = 0x7000 | 29 << 5;
+        addr.increment_y();
+        assert_eq!(addr.v, 0x0800);
+        addr.v = 0x7000 | 31 << 5;
+        addr.increment_y();
+        assert_eq!(addr.v, 0);
+
+        addr.t = 0x7fff;
+        addr.copy_x();
+        assert_eq!(addr.v, 0x041f);
+        addr.copy_y();
+        assert_eq!(addr.v, 0x7fff);
+    }
+}
@@ -0,0 +1,124 @@
+// background fetch latches and shift registers
+// https://www.nesdev.org/wiki/PPU_rendering
+// the PPU fetches a tile 8 dots ahead: nametable, attribute, then the two pattern bytes
+// go into the latches, and every 8 dots they are loaded into the low byte of the shifters
+// while the high byte is being drawn
+#[derive(Debug)]
+pub struct Pipeline {
+    pub next_tile: u8,
+    // VRAM index of the next tile's nametable byte
+    pub next_tile_idx: usize,
+    // background palette (0-3) of the next tile
+    pub next_palette: u8,
+    pub next_low: u8,
+    pub next_high: u8,
+    pattern_low: u16,
+    pattern_high: u16,
+    // the palette bits repeated for every pixel of the tile
+    palette_low: u16,
+    palette_high: u16,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline {
+            next_tile: 0,
+            next_tile_idx: 0,
+            next_palette: 0,
+            next_low: 0,
+            next_high: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            palette_low: 0,
+            palette_high: 0,
+        }
+    }
+
+    pub fn reload(&mut self) {
+        self.pattern_low = self.pattern_low & 0xff00 | self.next_low as u16;
+        self.pattern_high = self.pattern_high & 0xff00 | self.next_high as u16;
+        self.palette_low = self.palette_low & 0xff00 | if self.next_palette & 0b01 != 0 { 0xff } else { 0 };
+        self.palette_high = self.palette_high & 0xff00 | if self.next_palette & 0b10 != 0 { 0xff } else { 0 };
+    }
+
+    pub fn shift(&mut self) {
+        self.pattern_low <<= 1;
+        self.pattern_high <<= 1;
+        self.palette_low <<= 1;
+        self.palette_high <<= 1;
+    }
+
+    // (palette << 2 | color) of the pixel fine_x from the left of the high byte, 0 if transparent
+    pub fn pixel(&self, fine_x: u8) -> u8 {
+        let bit = 0x8000 >> fine_x;
+        let color = ((self.pattern_high & bit != 0) as u8) << 1 | (self.pattern_low & bit != 0) as u8;
+        if color == 0 {
+            return 0;
+        }
+        let palette = ((self.palette_high & bit != 0) as u8) << 1 | (self.palette_low & bit != 0) as u8;
+        palette << 2 | color
+    }
+}
+
+// pattern bytes of the tile rows fetched in earlier frames, one per nametable byte and fine Y
+// a row is read from CHR again only when its nametable byte, CHR or the pattern table changes
+// the palette is applied to the pixels later, so palette writes don't invalidate it
+#[derive(Debug)]
+pub struct TileRows {
+    // (nametable byte version, CHR version, pattern table) each row was fetched with
+    keys: Vec<Option<(u32, u32, u16)>>,
+    rows: Vec<(u8, u8)>,
+}
+
+impl TileRows {
+    pub fn new() -> Self {
+        TileRows {
+            keys: vec![None; 2048 * 8],
+            rows: vec![(0, 0); 2048 * 8],
+        }
+    }
+
+    // the (low, high) pattern bytes cached for a row, if they are still current
+    pub fn get(&self, tile_idx: usize, fine_y: u16, key: (u32, u32, u16)) -> Option<(u8, u8)> {
+        let row = tile_idx * 8 + fine_y as usize;
+        if self.keys[row] == Some(key) {
+            Some(self.rows[row])
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, tile_idx: usize, fine_y: u16, key: (u32, u32, u16), pattern: (u8, u8)) {
+        let row = tile_idx * 8 + fine_y as usize;
+        self.keys[row] = Some(key);
+        self.rows[row] = pattern;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        let mut pipeline = Pipeline::new();
+        pipeline.next_low = 0b1000_0001;
+        pipeline.next_high = 0b1100_0000;
+        pipeline.next_palette = 2;
+        pipeline.reload();
+        for _ in 0..8 {
+            pipeline.shift();
+        }
+        // the next tile is loaded while this one is drawn
+        pipeline.next_low = 0xff;
+        pipeline.next_high = 0;
+        pipeline.next_palette = 1;
+        pipeline.reload();
+        assert_eq!(pipeline.pixel(0), 2 << 2 | 3);
+        assert_eq!(pipeline.pixel(1), 2 << 2 | 2);
+        assert_eq!(pipeline.pixel(2), 0);
+        assert_eq!(pipeline.pixel(7), 2 << 2 | 1);
+        pipeline.shift();
+        assert_eq!(pipeline.pixel(7), 1 << 2 | 1);
+    }
+}
@@ -1,9 +1,21 @@
 mod address;
+mod background;
 mod control;
 mod mask;
-mod scroll;
 mod status;
 
+use heatmap::AccessCounter;
+use cartridge::Cartridge;
+use devlog;
+use state;
+use std::io::{self, Write};
+use std::fmt::Debug;
+use ines::{Rom, TvSystem};
+
+// background pixels, one per dot of the visible scanlines
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
 // PPU Memory Map
 //  _______________  $FFFF
 // | Mirrors       |
@@ -18,57 +30,117 @@ mod status;
 // | (CHR ROM)     |
 // |_______________| $0000
 
-#[derive(Debug, PartialEq)]
+// frames without a PPU register access before the open bus reads as 0 (about 600ms)
+const OPEN_BUS_DECAY_FRAMES: u16 = 36;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    // the first or second nametable for all four screens
+    SingleScreenA,
+    SingleScreenB,
+}
+
+// registers that decide how a scanline's sprites and colors show, captured when the scanline starts
+// the background is drawn dot by dot into Ppu::background instead
+#[derive(Debug, Clone, Copy)]
+pub struct LineState {
+    // palette indices are ANDed with $30
+    pub greyscale: bool,
+    // PPUMASK bits 5-7 (red, green, blue) moved down to bits 0-2
+    pub emphasis: u8,
+    pub show_sprites: bool,
+    // drawn in the leftmost 8 pixels
+    pub left_sprites: bool,
 }
 
 #[derive(Debug)]
 pub struct Ppu {
-    pub chr_rom: Vec<u8>,
+    pub cartridge: Cartridge,
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
+    // bumped on every write through PPUDATA, so cached tile rows are fetched again when they change
+    pub vram_versions: [u32; 2048],
+    pub chr_version: u32,
     pub oam_data: [u8; 256],
     pub oam_addr: u8,
-    pub mirroring: Mirroring,
     pub ctrl: control::ControlRegister,
     mask: mask::MaskRegister,
     addr: address::AddrRegister,
     stat: status::StatusRegister,
-    scroll: scroll::ScrollRegister,
+    pipeline: background::Pipeline,
+    tile_rows: background::TileRows,
+    // (palette << 2 | color) of each background pixel, 0 where it's transparent or hidden
+    pub background: Vec<u8>,
+    // sprite 0 on the current scanline, for sprite 0 hits
+    sprite_zero_row: Option<[u8; 8]>,
+    // one per visible scanline, so writes in the middle of a frame (split screens) show up
+    pub lines: Vec<LineState>,
+    next_line: u16,
     internal_buf: u8,
+    // last value on the CPU-PPU data bus, read back from write-only registers
+    // it fades to 0 when nothing refreshes it for about 600ms
+    open_bus: u8,
+    open_bus_frames: u16,
     // manage tick
     scanline: u16,
+    // overclocking: idle scanlines inserted between rendering and vblank
+    pub extra_scanlines: u16,
+    extra_scanlines_done: u16,
+    // at most 8 sprites per scanline, as on hardware; off trades flicker for accuracy
+    pub sprite_limit: bool,
     cycles: usize,
+    // dots of the last tick left over after a frame was completed
+    pending_dots: usize,
     pub nmi_interrupt: Option<u8>,
+    pub vram_access: Option<AccessCounter>,
 }
 
 impl Ppu {
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(cartridge: Cartridge) -> Self {
         Ppu {
-            chr_rom: chr_rom,
+            cartridge,
             palette_table: [0; 32],
             vram: [0; 2048],
+            vram_versions: [0; 2048],
+            chr_version: 0,
             oam_data: [0; 256],
             oam_addr: 0,
-            mirroring: mirroring,
             ctrl: control::ControlRegister::new(),
             mask: mask::MaskRegister::new(),
             addr: address::AddrRegister::new(),
             stat: status::StatusRegister::new(),
-            scroll: scroll::ScrollRegister::new(),
+            pipeline: background::Pipeline::new(),
+            tile_rows: background::TileRows::new(),
+            background: vec![0; WIDTH * HEIGHT],
+            sprite_zero_row: None,
+            lines: vec![LineState {
+                greyscale: false,
+                emphasis: 0,
+                show_sprites: false,
+                left_sprites: false,
+            }; HEIGHT],
+            next_line: 0,
             internal_buf: 0,
+            open_bus: 0,
+            open_bus_frames: 0,
             scanline: 0,
+            extra_scanlines: 0,
+            extra_scanlines_done: 0,
+            sprite_limit: true,
             cycles: 0,
+            pending_dots: 0,
             nmi_interrupt: None,
+            vram_access: None,
         }
     }
 
     pub fn write_to_ctrl(&mut self, value: u8) {
         let prev_nmi_status = self.ctrl.generate_vbalnk_nmi();
         self.ctrl.update(value);
+        self.addr.set_nametable(value);
         if !prev_nmi_status && self.ctrl.generate_vbalnk_nmi() && self.stat.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
@@ -79,7 +151,7 @@ impl Ppu {
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.addr.write_scroll(value);
     }
 
     pub fn write_to_ppu_addr(&mut self, value: u8) {
@@ -102,28 +174,70 @@ impl Ppu {
         }
     }
 
-    pub fn read_oam_data(&self) -> u8 {
+    // every access to a PPU register drives the data bus
+    pub fn write_open_bus(&mut self, value: u8) {
+        self.open_bus = value;
+        self.open_bus_frames = 0;
+    }
+
+    // reads of the write-only registers
+    pub fn read_open_bus(&self) -> u8 {
+        self.open_bus
+    }
+
+    pub fn read_oam_data(&mut self) -> u8 {
+        // OAM reads see sprite evaluation on hardware
+        if self.rendering() {
+            devlog::hit("$2004 read during rendering");
+        }
+        let data = self.peek_oam_data();
+        self.write_open_bus(data);
+        data
+    }
+
+    // what read_oam_data would return, without touching any state
+    pub fn peek_oam_data(&self) -> u8 {
         self.oam_data[self.oam_addr as usize]
     }
 
+    // what read_status would return, without clearing vblank or the write latch
+    pub fn peek_status(&self) -> u8 {
+        // the low 5 bits are not driven
+        self.stat.snapshot() & 0b1110_0000 | self.open_bus & 0b0001_1111
+    }
+
     pub fn read_status(&mut self) -> u8 {
-        let data = self.stat.snapshot();
+        let data = self.peek_status();
+        self.write_open_bus(data);
         self.stat.clear_vblank_status();
         self.addr.reset_latch();
-        self.scroll.reset_latch();
         data
     }
 
     fn inc_vram_addr(&mut self) {
+        // while rendering the access bumps both scroll counters instead, as on hardware
+        if (self.scanline < 240 || self.scanline == 261) && self.rendering_enabled() {
+            self.addr.increment_x();
+            self.addr.increment_y();
+            return;
+        }
         self.addr.inc(self.ctrl.inc_vram_addr());
     }
 
     pub fn write_to_data(&mut self, value: u8) {
         let addr = self.addr.get();
         match addr {
-            0..=0x1fff => panic!("Cannot write to character ROM"),
+            0..=0x1fff => {
+                self.cartridge.borrow_mut().chr_write(addr, value);
+                self.chr_version = self.chr_version.wrapping_add(1);
+            }
             0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+                let idx = self.mirror_vram_addr(addr) as usize;
+                if let Some(counter) = self.vram_access.as_mut() {
+                    counter.write(idx);
+                }
+                self.vram[idx] = value;
+                self.vram_versions[idx] = self.vram_versions[idx].wrapping_add(1);
             }
             0x3000..=0x3eff => unimplemented!("Shouldn't write here"),
             0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
@@ -144,25 +258,65 @@ impl Ppu {
         let addr = self.addr.get();
         self.inc_vram_addr();
 
-        match addr {
+        let data = match addr {
             0x0000..=0x1fff => {
                 let res = self.internal_buf;
-                self.internal_buf = self.chr_rom[addr as usize];
+                self.internal_buf = self.read_chr(addr);
                 res
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let res = self.internal_buf;
-                self.internal_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                let idx = self.mirror_vram_addr(addr) as usize;
+                if let Some(counter) = self.vram_access.as_mut() {
+                    counter.read(idx);
+                }
+                self.internal_buf = self.vram[idx];
                 res
             }
-            0x3000..=0x3eff => panic!("Unexpected access"),
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let origin = addr - 0x10;
-                self.palette_table[(origin - 0x3f00) as usize]
+            0x3f00..=0x3fff => {
+                // not buffered, the buffer gets the nametable byte underneath instead
+                self.internal_buf = self.vram[self.mirror_vram_addr(addr - 0x1000) as usize];
+                self.read_palette(addr)
             }
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
             _ => panic!("unexpected"),
+        };
+        self.write_open_bus(data);
+        data
+    }
+
+    // what read_data would return, without moving the address or filling the buffer
+    pub fn peek_data(&self) -> u8 {
+        match self.addr.get() {
+            addr @ 0x3f00..=0x3fff => self.read_palette(addr),
+            _ => self.internal_buf,
+        }
+    }
+
+    // registers, then VRAM, OAM and palette as hex
+    pub fn write_state<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "ppu: scanline:{} cycle:{} ctrl:{:02X} mask:{:02X} status:{:02X} v:{:04X} t:{:04X} x:{} oam addr:{:02X}",
+            self.scanline, self.cycles, self.ctrl.bits(), self.mask.bits(), self.stat.snapshot(),
+            self.addr.v, self.addr.t, self.addr.x, self.oam_addr)?;
+        state::write_hex(out, "vram", 0x2000, &self.vram)?;
+        state::write_hex(out, "oam", 0, &self.oam_data)?;
+        state::write_hex(out, "palette", 0x3f00, &self.palette_table)
+    }
+
+    fn read_palette(&self, addr: u16) -> u8 {
+        let mut idx = (addr - 0x3f00) as usize % 32;
+        if idx >= 0x10 && idx.is_multiple_of(4) {
+            idx -= 0x10;
         }
+        // palette entries are 6 bits, the top 2 come from the open bus
+        self.palette_table[idx] & 0x3f | self.open_bus & 0xc0
+    }
+
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.cartridge.borrow().chr_read(addr)
+    }
+
+    fn read_nametable(&self, addr: u16) -> u8 {
+        self.vram[self.mirror_vram_addr(addr) as usize]
     }
 
     // PPU memory address to VRAM index
@@ -172,47 +326,351 @@ impl Ppu {
     // Vertical:
     //   [ A ] [ B ]
     //   [ a ] [ b ]
+    // Single screen:
+    //   [ A ] [ a ]
+    //   [ a ] [ a ]
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         // mirror down 0x3000-0x3eff to 0x2000-0x2eff
         let mirrored_vram = addr & 0b10111111111111;
         let vram_index = mirrored_vram - 0x2000;
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        match (self.cartridge.borrow().mirroring(), name_table) {
             (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
             (Mirroring::Horizontal, 2) => vram_index - 0x400,
             (Mirroring::Horizontal, 1) => vram_index - 0x400,
             (Mirroring::Horizontal, 3) => vram_index - 0x800,
+            (Mirroring::SingleScreenA, _) => vram_index % 0x400,
+            (Mirroring::SingleScreenB, _) => 0x400 + vram_index % 0x400,
             _ => vram_index,
         }
     }
 
-    pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
+    // colors (0-3, 0 is transparent) of a sprite's pixels on a scanline, left to right
+    // None if the sprite doesn't cover the scanline
+    pub fn sprite_pixels(&self, sprite: usize, scanline: usize) -> Option<[u8; 8]> {
+        let oam = &self.oam_data[sprite * 4..sprite * 4 + 4];
+        // sprites are delayed by one scanline
+        let top = oam[0] as usize + 1;
+        let height = self.ctrl.sprite_size() as usize;
+        if scanline < top || scanline >= top + height {
+            return None;
+        }
+        let attr = oam[2];
+        let flip_vertical = attr >> 7 & 1 == 1;
+        let flip_horizontal = attr >> 6 & 1 == 1;
+        let mut row = scanline - top;
+        if flip_vertical {
+            row = height - 1 - row;
+        }
+        let index = oam[1] as u16;
+        // 8x16 sprites take the pattern table from bit 0 of the index
+        let tile_addr = if height == 16 {
+            (index & 1) * 0x1000 + (index & 0xfe) * 16 + if row >= 8 { 16 } else { 0 }
+        } else {
+            self.ctrl.sprite_pattern_addr() + index * 16
+        };
+        let pattern = tile_addr + (row % 8) as u16;
+        let lower = self.read_chr(pattern);
+        let upper = self.read_chr(pattern + 8);
+        let mut pixels = [0; 8];
+        for (px, pixel) in pixels.iter_mut().enumerate() {
+            let bit = if flip_horizontal { px } else { 7 - px };
+            *pixel = ((upper >> bit) & 1) << 1 | ((lower >> bit) & 1);
+        }
+        Some(pixels)
+    }
+
+    // sprites drawn on a scanline, in OAM order
+    // the hardware only finds the first 8, unless sprite_limit is turned off
+    pub fn evaluate_sprites(&self, scanline: usize) -> Vec<usize> {
+        let height = self.ctrl.sprite_size() as usize;
+        let limit = if self.sprite_limit { 8 } else { 64 };
+        (0..64)
+            .filter(|&i| {
+                let top = self.oam_data[i * 4] as usize + 1;
+                scanline >= top && scanline < top + height
+            })
+            .take(limit)
+            .collect()
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask.contains(mask::MaskRegister::SHOW_BACKGROUND)
+            || self.mask.contains(mask::MaskRegister::SHOW_SPRITES)
+    }
+
+    // on a visible scanline with background or sprites enabled
+    fn rendering(&self) -> bool {
+        self.scanline < 240 && self.rendering_enabled()
+    }
+
+    fn capture_line(&mut self) {
+        self.lines[self.scanline as usize] = LineState {
+            greyscale: self.mask.contains(mask::MaskRegister::GREYSCALE),
+            emphasis: self.mask.bits() >> 5,
+            show_sprites: self.mask.contains(mask::MaskRegister::SHOW_SPRITES),
+            left_sprites: self.mask.contains(mask::MaskRegister::LEFTMOST_8PXL_SPRITE),
+        };
+    }
+
+    // one of the 8 steps fetching the next background tile
+    fn fetch_background(&mut self, step: usize) {
+        match step {
+            0 => {
+                self.pipeline.reload();
+                let idx = self.mirror_vram_addr(self.addr.tile_addr()) as usize;
+                self.pipeline.next_tile_idx = idx;
+                self.pipeline.next_tile = self.vram[idx];
+            }
+            2 => {
+                let attr = self.read_nametable(self.addr.attribute_addr());
+                // 2 bits for each 2x2 tile quadrant, picked by bit 1 of coarse Y and X
+                let shift = (self.addr.v >> 4) & 4 | self.addr.v & 2;
+                self.pipeline.next_palette = attr >> shift & 0b11;
+            }
+            // both pattern bytes, from the cached row when the tile hasn't changed
+            4 => {
+                let idx = self.pipeline.next_tile_idx;
+                let fine_y = self.addr.fine_y();
+                let bank = self.ctrl.bkgnd_pattern_addr();
+                let key = (self.vram_versions[idx], self.chr_version, bank);
+                let (low, high) = match self.tile_rows.get(idx, fine_y, key) {
+                    Some(pattern) => pattern,
+                    None => {
+                        let addr = bank + self.pipeline.next_tile as u16 * 16 + fine_y;
+                        let pattern = (self.read_chr(addr), self.read_chr(addr + 8));
+                        self.tile_rows.insert(idx, fine_y, key, pattern);
+                        pattern
+                    }
+                };
+                self.pipeline.next_low = low;
+                self.pipeline.next_high = high;
+            }
+            7 => self.addr.increment_x(),
+            _ => (),
+        }
+    }
+
+    fn output_pixel(&mut self, x: usize) {
+        let y = self.scanline as usize;
+        let shown = self.mask.contains(mask::MaskRegister::SHOW_BACKGROUND)
+            && (x >= 8 || self.mask.contains(mask::MaskRegister::LEFTMOST_8PXL_BACKGROUND));
+        let pixel = if shown { self.pipeline.pixel(self.addr.x) } else { 0 };
+        self.background[y * WIDTH + x] = pixel;
+        if x == 0 {
+            self.sprite_zero_row = self.sprite_pixels(0, y);
+        }
+        self.update_sprite_zero_hit(x, pixel);
+    }
+
+    // an opaque pixel of sprite 0 over an opaque background pixel
+    fn update_sprite_zero_hit(&mut self, x: usize, background: u8) {
+        let rendering = self.mask.contains(mask::MaskRegister::SHOW_BACKGROUND)
+            && self.mask.contains(mask::MaskRegister::SHOW_SPRITES);
+        let left_clipped = !self.mask.contains(mask::MaskRegister::LEFTMOST_8PXL_BACKGROUND)
+            || !self.mask.contains(mask::MaskRegister::LEFTMOST_8PXL_SPRITE);
+        // no hit at x=255, nor in the clipped left column
+        if !rendering || x == 255 || (x < 8 && left_clipped) || background & 0b11 == 0 {
+            return;
+        }
+        let sprite_x = self.oam_data[3] as usize;
+        if let Some(pixels) = self.sprite_zero_row {
+            if x >= sprite_x && x < sprite_x + 8 && pixels[x - sprite_x] != 0 {
+                self.stat.set_sprite_zero_hit(true);
+            }
+        }
+    }
+
+    // background work of the current dot
+    // pixel x is output on dot x + 1, and the first two tiles of a scanline
+    // are fetched at the end of the one before
+    fn render_dot(&mut self) {
+        let dot = self.cycles;
+        let pre_render = self.scanline == 261;
+        if (self.scanline < 240 || pre_render) && self.rendering_enabled() {
+            if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+                self.pipeline.shift();
+                self.fetch_background((dot - 1) % 8);
+            }
+            if dot == 256 {
+                self.addr.increment_y();
+            }
+            if dot == 257 {
+                self.addr.copy_x();
+            }
+            if pre_render && (280..=304).contains(&dot) {
+                self.addr.copy_y();
+            }
+        }
+        if self.scanline < 240 && (1..=256).contains(&dot) {
+            self.output_pixel(dot - 1);
+        }
+    }
+
+    // one dot, true when it completes a frame
+    fn step(&mut self) -> bool {
+        self.cycles += 1;
         if self.cycles >= 341 {
-            self.cycles -= 341;
+            self.cycles = 0;
+            // the CPU gets extra time before vblank, the picture is already complete
+            if self.scanline == 240 && self.extra_scanlines_done < self.extra_scanlines {
+                self.extra_scanlines_done += 1;
+                return false;
+            }
             self.scanline += 1;
             // must trigger NMI interruption and refresh screen
             // while scanline is in range 241 ~ 262
             if self.scanline == 241 {
                 self.stat.set_vblank_status(true);
-                self.stat.set_sprite_zero_hit(false);
                 if self.ctrl.generate_vbalnk_nmi() {
                     self.nmi_interrupt = Some(1);
                 }
             }
             if self.scanline >= 262 {
+                self.open_bus_frames += 1;
+                if self.open_bus_frames >= OPEN_BUS_DECAY_FRAMES {
+                    self.open_bus = 0;
+                }
                 self.scanline = 0;
+                self.next_line = 0;
+                self.extra_scanlines_done = 0;
                 self.nmi_interrupt = None;
                 self.stat.set_sprite_zero_hit(false);
                 self.stat.clear_vblank_status();
                 return true;
             }
         }
-        return false;
+        self.render_dot();
+        false
+    }
+
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        // on the first tick of a scanline, so line 0 is captured after the frame callback
+        if self.scanline < 240 && self.next_line == self.scanline {
+            self.capture_line();
+            self.next_line += 1;
+        }
+        self.pending_dots += cycles as usize;
+        while self.pending_dots > 0 {
+            self.pending_dots -= 1;
+            // the rest of the dots run after the frame callback
+            if self.step() {
+                return true;
+            }
+        }
+        false
+    }
+
+    // (scanline, dot) the PPU is at
+    pub fn position(&self) -> (u16, usize) {
+        (self.scanline, self.cycles)
+    }
+
+    // inside one of the extra scanlines added by overclocking
+    pub fn is_overclocking(&self) -> bool {
+        self.scanline == 240 && self.extra_scanlines_done > 0
     }
 
     pub fn new_empty_rom() -> Self {
-        Ppu::new(vec![0; 2048], Mirroring::Horizontal)
+        Ppu::new_empty_rom_with_mirroring(Mirroring::Horizontal)
+    }
+
+    pub fn new_empty_rom_with_mirroring(mirroring: Mirroring) -> Self {
+        let rom = Rom {
+            prg_rom: vec![0; 0x4000],
+            chr_rom: vec![0; 0x2000],
+            chr_ram: true,
+            mapper: 0,
+            mirroring,
+            battery: false,
+            tv_system: TvSystem::Ntsc,
+        };
+        Ppu::new(rom.into_cartridge().unwrap())
+    }
+}
+
+// the PPU as the CPU bus sees it: registers at $2000-$2007, OAM DMA and the clock
+// Ppu is the real one, BusBuilder::ppu takes another, e.g. a stub that records accesses in tests
+pub trait PpuDevice: Debug {
+    // addr is $2000-$2007, mirrors are folded by the bus
+    fn read_register(&mut self, addr: u16) -> u8;
+    // what read_register would return, without side effects
+    fn peek_register(&self, addr: u16) -> u8;
+    fn write_register(&mut self, addr: u16, data: u8);
+    fn write_oam_dma(&mut self, data: &[u8; 256]);
+    // PPU cycles, true when a frame was completed
+    fn tick(&mut self, cycles: u8) -> bool;
+    fn poll_nmi(&mut self) -> Option<u8>;
+    // (scanline, dot)
+    fn position(&self) -> (u16, usize) {
+        (0, 0)
+    }
+    // the real PPU for renderers and settings, None for stand-ins
+    fn as_ppu(&self) -> Option<&Ppu> {
+        None
+    }
+    fn as_ppu_mut(&mut self) -> Option<&mut Ppu> {
+        None
+    }
+}
+
+impl PpuDevice for Ppu {
+    fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => self.read_status(),
+            0x2004 => self.read_oam_data(),
+            0x2007 => self.read_data(),
+            // write only
+            _ => self.read_open_bus(),
+        }
+    }
+
+    fn peek_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => self.peek_status(),
+            0x2004 => self.peek_oam_data(),
+            0x2007 => self.peek_data(),
+            _ => self.read_open_bus(),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        self.write_open_bus(data);
+        match addr {
+            0x2000 => self.write_to_ctrl(data),
+            0x2001 => self.write_to_mask(data),
+            // read only, the write only reaches the open bus
+            0x2002 => (),
+            0x2003 => self.write_to_oam_addr(data),
+            0x2004 => self.write_to_oam_data(data),
+            0x2005 => self.write_to_scroll(data),
+            0x2006 => self.write_to_ppu_addr(data),
+            _ => self.write_to_data(data),
+        }
+    }
+
+    fn write_oam_dma(&mut self, data: &[u8; 256]) {
+        Ppu::write_oam_dma(self, data);
+    }
+
+    fn tick(&mut self, cycles: u8) -> bool {
+        Ppu::tick(self, cycles)
+    }
+
+    fn poll_nmi(&mut self) -> Option<u8> {
+        self.nmi_interrupt.take()
+    }
+
+    fn position(&self) -> (u16, usize) {
+        Ppu::position(self)
+    }
+
+    fn as_ppu(&self) -> Option<&Ppu> {
+        Some(self)
+    }
+
+    fn as_ppu_mut(&mut self) -> Option<&mut Ppu> {
+        Some(self)
     }
 }
 
@@ -221,6 +679,194 @@ impl Ppu {
 pub mod test {
     use super::*;
 
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = Ppu::new_empty_rom();
+        // tile 1 is solid
+        for i in 0..16 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        // background tile at (8, 8)
+        ppu.vram[33] = 1;
+        // sprite 0 at (12, 5 + 1)
+        ppu.oam_data[0..4].copy_from_slice(&[5, 1, 0, 12]);
+
+        // rendering disabled
+        for _ in 0..262 * 341 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.stat.snapshot() & 0b0100_0000, 0);
+
+        ppu.write_to_mask(0b0001_1110);
+        while ppu.scanline < 8 {
+            ppu.tick(1);
+            assert_eq!(ppu.stat.snapshot() & 0b0100_0000, 0);
+        }
+        while ppu.cycles <= 12 {
+            assert_eq!(ppu.stat.snapshot() & 0b0100_0000, 0);
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.stat.snapshot() & 0b0100_0000, 0b0100_0000);
+
+        // cleared at the end of vblank
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.stat.snapshot() & 0b0100_0000, 0);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut ppu = Ppu::new_empty_rom();
+        for i in (0..256).step_by(4) {
+            ppu.oam_data[i] = 0xff;
+        }
+        // 9 sprites on scanlines 11-18, one more on 15-22
+        for i in 0..9 {
+            ppu.oam_data[i * 4] = 10;
+        }
+        ppu.oam_data[20 * 4] = 14;
+        assert_eq!(ppu.evaluate_sprites(10), vec![]);
+        assert_eq!(ppu.evaluate_sprites(11), (0..8).collect::<Vec<usize>>());
+        assert_eq!(ppu.evaluate_sprites(19), vec![20]);
+
+        ppu.sprite_limit = false;
+        assert_eq!(ppu.evaluate_sprites(15), vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 20]);
+    }
+
+    #[test]
+    fn test_mid_frame_scroll() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.write_to_mask(0b0000_1000);
+        ppu.write_to_scroll(0);
+        ppu.write_to_scroll(16);
+        // vertical scroll is loaded on the pre-render scanline
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.addr.v & 0x7be0, 2 << 5);
+        while ppu.scanline < 32 {
+            ppu.tick(1);
+        }
+        // status bar split: X = 100, Y = 50 in the last nametable
+        ppu.write_to_scroll(100);
+        ppu.write_to_scroll(50);
+        ppu.write_to_ctrl(0b11);
+        assert_eq!((ppu.addr.t, ppu.addr.x), (0x2ccc, 4));
+        // only X and the horizontal nametable take effect at the end of the scanline,
+        // the next scanline continues at Y = 49 with the first two tiles already fetched
+        while ppu.scanline < 33 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.addr.v, 0x1000 | 0x0400 | 6 << 5 | 14);
+
+        // the next frame starts with the new vertical scroll
+        while !ppu.tick(1) {}
+        assert_eq!(ppu.addr.v & 0x7be0, ppu.addr.t & 0x7be0);
+    }
+
+    #[test]
+    fn test_mid_scanline_address_write() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.write_to_mask(0b0000_1010);
+        // tile 1 is solid, only in the top left corner
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        ppu.vram[0] = 1;
+        while ppu.scanline < 100 || ppu.cycles < 128 {
+            ppu.tick(1);
+        }
+        // v points at the top left tile from the next fetch on,
+        // which is drawn after the two tiles already in the pipeline
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        while ppu.scanline < 101 {
+            ppu.tick(1);
+        }
+        let line = &ppu.background[100 * WIDTH..101 * WIDTH];
+        assert!(line[136..144].iter().all(|&pixel| pixel == 0));
+        assert!(line[144..152].iter().all(|&pixel| pixel == 1));
+        assert!(line[152..].iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn test_tile_row_cache() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.write_to_mask(0b0000_1010);
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        let run_frames = |ppu: &mut Ppu| {
+            while !ppu.tick(1) {}
+            while !ppu.tick(1) {}
+        };
+        run_frames(&mut ppu);
+        assert_eq!(ppu.background[0], 0);
+
+        // written behind the PPU's back, the cached row is still used
+        ppu.vram[0] = 1;
+        run_frames(&mut ppu);
+        assert_eq!(ppu.background[0], 0);
+
+        // written through PPUDATA in vblank
+        while ppu.scanline != 241 {
+            ppu.tick(1);
+        }
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_data(1);
+        run_frames(&mut ppu);
+        assert_eq!(ppu.background[0], 1);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.write_open_bus(0x5a);
+        assert_eq!(ppu.read_open_bus(), 0x5a);
+        // status fills its low bits from the bus
+        ppu.stat.set_vblank_status(true);
+        assert_eq!(ppu.read_status(), 0x80 | 0x1a);
+        assert_eq!(ppu.read_open_bus(), 0x9a);
+
+        // decays after about 600ms without accesses
+        for _ in 0..(OPEN_BUS_DECAY_FRAMES as usize - 1) * 262 * 341 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.read_open_bus(), 0x9a);
+        for _ in 0..262 * 341 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.read_open_bus(), 0);
+    }
+
+    #[test]
+    fn test_palette_read_buffer() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.palette_table[0x01] = 0x2c;
+        ppu.vram[0x701] = 0x66;
+        ppu.write_open_bus(0xc0);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x01);
+        // palette reads are immediate, with the top 2 bits from the open bus
+        assert_eq!(ppu.read_data(), 0xc0 | 0x2c);
+        // the buffer holds the nametable byte under $3F01 ($2F01)
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_extra_scanlines() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.extra_scanlines = 10;
+        let mut cycles = 0;
+        while !ppu.tick(1) {
+            cycles += 1;
+            if ppu.is_overclocking() {
+                assert!(!ppu.stat.is_in_vblank());
+            }
+        }
+        assert_eq!(cycles + 1, 341 * (262 + 10));
+    }
+
     #[test]
     fn test_ppu_vram_writes() {
         let mut ppu = Ppu::new_empty_rom();
@@ -290,7 +936,7 @@ pub mod test {
 
     #[test]
     fn test_vram_vertical_mirror() {
-        let mut ppu = Ppu::new(vec![0; 2048], Mirroring::Vertical);
+        let mut ppu = Ppu::new_empty_rom_with_mirroring(Mirroring::Vertical);
 
         ppu.write_to_ppu_addr(0x20);
         ppu.write_to_ppu_addr(0x05);
@@ -336,6 +982,19 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_ppu_single_screen_mirroring() {
+        let mut ppu = Ppu::new_empty_rom_with_mirroring(Mirroring::SingleScreenB);
+        ppu.write_to_ppu_addr(0x2c);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        let ppu = Ppu::new_empty_rom_with_mirroring(Mirroring::SingleScreenA);
+        assert_eq!(ppu.mirror_vram_addr(0x2405), 0x0005);
+        assert_eq!(ppu.mirror_vram_addr(0x2fff), 0x03ff);
+    }
+
     #[test]
     fn test_ppu_vram_mirroring() {
         let mut ppu = Ppu::new_empty_rom();
@@ -1,15 +1,39 @@
+use render::palette;
+
 #[derive(Debug)]
 pub struct Frame {
     pub data: Vec<u8>,
+    // NES palette index (0x00 ~ 0x3f) of each pixel
+    pub indexed: Vec<u8>,
+    // PPUMASK color emphasis (bit 0: red, 1: green, 2: blue) of each line
+    pub emphasis: Vec<u8>,
+    // RGB of each palette index for each emphasis, see palette::ColorAdjust
+    palettes: Vec<[(u8, u8, u8); 64]>,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            indexed: vec![0; (Frame::WIDTH) * (Frame::HIGHT)],
+            emphasis: vec![0; Frame::HIGHT],
+            palettes: palette::emphasized(&palette::SYSTEM_PALETTE),
+        }
+    }
+
+    // switch the palette used for RGB output, the current picture is converted too
+    pub fn set_palette(&mut self, palette: [(u8, u8, u8); 64]) {
+        self.palettes = palette::emphasized(&palette);
+        self.data = self.to_rgb(&palette);
+    }
+
+    // emphasis applies to the pixels set on the line afterwards
+    pub fn set_emphasis(&mut self, y: usize, emphasis: u8) {
+        if y < Frame::HIGHT {
+            self.emphasis[y] = emphasis & 0b111;
         }
     }
 
@@ -21,4 +45,61 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
-}
\ No newline at end of file
+
+    // set a pixel by palette index, keeping the RGB buffer in sync
+    pub fn set_indexed_pixel(&mut self, x: usize, y: usize, idx: u8) {
+        let pos = y * Frame::WIDTH + x;
+        if x < Frame::WIDTH && pos < self.indexed.len() {
+            let idx = idx & 0x3f;
+            self.indexed[pos] = idx;
+            let rgb = self.palettes[self.emphasis[y] as usize][idx as usize];
+            self.set_pixel(x, y, rgb);
+        }
+    }
+
+    // convert the indexed buffer to RGB24 with another palette
+    pub fn to_rgb(&self, palette: &[(u8, u8, u8); 64]) -> Vec<u8> {
+        let palettes = palette::emphasized(palette);
+        let mut data = Vec::with_capacity(self.indexed.len() * 3);
+        for (pos, idx) in self.indexed.iter().enumerate() {
+            let emphasis = self.emphasis[pos / Frame::WIDTH] as usize;
+            let (r, g, b) = palettes[emphasis][*idx as usize & 0x3f];
+            data.push(r);
+            data.push(g);
+            data.push(b);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_indexed_pixel() {
+        let mut frame = Frame::new();
+        frame.set_indexed_pixel(1, 2, 0x21);
+        let pos = 2 * Frame::WIDTH + 1;
+        assert_eq!(frame.indexed[pos], 0x21);
+        let rgb = palette::SYSTEM_PALETTE[0x21];
+        assert_eq!(&frame.data[pos * 3..pos * 3 + 3], &[rgb.0, rgb.1, rgb.2]);
+
+        let mut swapped = palette::SYSTEM_PALETTE;
+        swapped[0x21] = (1, 2, 3);
+        assert_eq!(&frame.to_rgb(&swapped)[pos * 3..pos * 3 + 3], &[1, 2, 3]);
+
+        frame.set_palette(swapped);
+        assert_eq!(&frame.data[pos * 3..pos * 3 + 3], &[1, 2, 3]);
+        frame.set_indexed_pixel(0, 0, 0x21);
+        assert_eq!(&frame.data[0..3], &[1, 2, 3]);
+
+        // red emphasis darkens green and blue
+        frame.set_emphasis(2, 0b001);
+        frame.set_indexed_pixel(1, 2, 0x30);
+        let white = palette::SYSTEM_PALETTE[0x30];
+        assert_eq!(frame.data[pos * 3], white.0);
+        assert!(frame.data[pos * 3 + 1] < white.1);
+        assert_eq!(&frame.to_rgb(&palette::SYSTEM_PALETTE)[pos * 3..pos * 3 + 3], &frame.data[pos * 3..pos * 3 + 3]);
+    }
+}
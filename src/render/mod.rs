@@ -1,22 +1,50 @@
 pub mod frame;
 pub mod palette;
+pub mod scale;
+mod surface;
 
 use ppu::Ppu;
+use self::surface::Surface;
 
-fn bg_palette(ppu: &Ppu, tile_column: usize, tile_row: usize) -> [u8; 4] {
-    let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = ppu.vram[0x3c0 + attr_table_idx];
+// what decided the color of a pixel, for the priority debug view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    // universal background color
+    Backdrop,
+    Background,
+    FrontSprite,
+    // a sprite with the priority bit set, over a transparent background pixel
+    BackSprite,
+    SpriteZero,
+}
 
-    let palette_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
-        (0, 0) => attr_byte & 0b11,
-        (1,0) => (attr_byte >> 2) & 0b11,
-        (0,1) => (attr_byte >> 4) & 0b11,
-        (1,1) => (attr_byte >> 6) & 0b11,
-        (_, _) => panic!(),
-    };
+impl Source {
+    pub fn debug_color(&self) -> (u8, u8, u8) {
+        match self {
+            Source::Backdrop => (0x20, 0x20, 0x20),
+            Source::Background => (0x30, 0x60, 0xff),
+            Source::FrontSprite => (0x30, 0xe0, 0x30),
+            Source::BackSprite => (0xf0, 0xd0, 0x20),
+            Source::SpriteZero => (0xff, 0x30, 0x30),
+        }
+    }
+}
 
-    let palette_start: usize = 1 + (palette_idx as usize) * 4;
-    [ppu.palette_table[0], ppu.palette_table[palette_start], ppu.palette_table[palette_start + 1], ppu.palette_table[palette_start + 2]]
+// what the last rendered frame was made of, kept for show_sources
+pub struct LastFrame {
+    // background pixels that are not color 0, for sprite priority
+    opaque: Surface<bool>,
+    // where each pixel came from
+    sources: Surface<Source>,
+}
+
+impl LastFrame {
+    pub fn new() -> Self {
+        LastFrame {
+            opaque: Surface::new(frame::Frame::WIDTH, frame::Frame::HIGHT, false),
+            sources: Surface::new(frame::Frame::WIDTH, frame::Frame::HIGHT, Source::Backdrop),
+        }
+    }
 }
 
 fn sprite_palette(ppu: &Ppu, palette_idx: u8) -> [u8; 4] {
@@ -24,80 +52,330 @@ fn sprite_palette(ppu: &Ppu, palette_idx: u8) -> [u8; 4] {
     [0, ppu.palette_table[start], ppu.palette_table[start + 1], ppu.palette_table[start + 2]]
 }
 
-// TODO: Use appropriate palette
+// greyscale keeps only the brightness column of the palette index
+fn color_mask(ppu: &Ppu, y: usize) -> u8 {
+    if ppu.lines[y].greyscale { 0x30 } else { 0x3f }
+}
+
 pub fn render(ppu: &Ppu, frame: &mut frame::Frame) {
+    render_with(ppu, &mut LastFrame::new(), frame);
+}
+
+// same as render, also keeping what each pixel came from
+pub fn render_with(ppu: &Ppu, last: &mut LastFrame, frame: &mut frame::Frame) {
+    const WIDTH: usize = frame::Frame::WIDTH;
     // draw background
-    // two nametables exist
-    let bank = ppu.ctrl.bkgnd_pattern_addr();
-    // TODO: just for now, lets use the first nametable
-    for i in 0 .. 0x3c0 {
-        let tile = ppu.vram[i] as u16;
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile = &ppu.chr_rom[
-            (bank + tile * 16) as usize ..= (bank + tile * 16 + 15) as usize];
-        let palette = bg_palette(ppu, tile_column, tile_row);
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            for x in (0..=7).rev() {
-                let val = (1 & upper) << 1 | (1 & lower);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                // TODO: just for now
-                let rgb = match val {
-                    0 => palette::SYSTEM_PALETTE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALETTE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALETTE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALETTE[palette[3] as usize],
-                    _ => panic!(),
-                };
-                frame.set_pixel(tile_column * 8 + x, tile_row * 8 + y, rgb);
-            }
+    // the PPU drew it while it ran, with the scroll and registers of each dot
+    for y in 0..frame::Frame::HIGHT {
+        frame.set_emphasis(y, ppu.lines[y].emphasis);
+        for x in 0..WIDTH {
+            let pixel = ppu.background[y * WIDTH + x];
+            // color 0 of every background palette is the universal background color
+            frame.set_indexed_pixel(x, y, color_mask(ppu, y) & ppu.palette_table[pixel as usize]);
+            let opaque = pixel & 0b11 != 0;
+            last.opaque.set(x, y, opaque);
+            last.sources.set(x, y, if opaque { Source::Background } else { Source::Backdrop });
         }
     }
     // draw sprites
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let tile_x = ppu.oam_data[i + 3] as usize;
-        let tile_y = ppu.oam_data[i] as usize;
-
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let flip_horizontal = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let palette_idx = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette = sprite_palette(ppu, palette_idx);
-        let bank: u16 = ppu.ctrl.sprite_pattern_addr();
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize ..= (bank + tile_idx * 16 + 15) as usize];
-
-        for y in 0 ..= 7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'xloop: for x in (0..=7).rev() {
-                let val = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match val {
-                    0 => continue 'xloop,
-                    1 => palette::SYSTEM_PALETTE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALETTE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALETTE[sprite_palette[3] as usize],
-                    _ => panic!(),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+    // the first sprite in OAM with an opaque pixel owns it, even when that sprite is behind
+    // the background and so hides the sprites after it
+    for y in 0..frame::Frame::HIGHT {
+        if !ppu.lines[y].show_sprites {
+            continue;
+        }
+        let mut sprite_drawn = [false; WIDTH];
+        for i in ppu.evaluate_sprites(y) {
+            let pixels = ppu.sprite_pixels(i, y).unwrap();
+            let attr = ppu.oam_data[i * 4 + 2];
+            let behind_background = attr >> 5 & 1 == 1;
+            let sprite_palette = sprite_palette(ppu, attr & 0b11);
+            let sprite_x = ppu.oam_data[i * 4 + 3] as usize;
+            for (px, &val) in pixels.iter().enumerate() {
+                let x = sprite_x + px;
+                if val == 0 || x >= WIDTH || sprite_drawn[x] || (x < 8 && !ppu.lines[y].left_sprites) {
+                    continue;
+                }
+                sprite_drawn[x] = true;
+                // sprites behind the background only show over color 0
+                if !(behind_background && last.opaque.get(x, y) == Some(true)) {
+                    frame.set_indexed_pixel(x, y, color_mask(ppu, y) & sprite_palette[val as usize]);
+                    last.sources.set(x, y, match (i, behind_background) {
+                        (0, _) => Source::SpriteZero,
+                        (_, true) => Source::BackSprite,
+                        (_, false) => Source::FrontSprite,
+                    });
                 }
             }
         }
     }
 }
+
+// replace the last rendered frame's colors with the color of each pixel's Source
+pub fn show_sources(last: &LastFrame, frame: &mut frame::Frame) {
+    for y in 0..frame::Frame::HIGHT {
+        for x in 0..frame::Frame::WIDTH {
+            let source = last.sources.get(x, y).unwrap();
+            frame.set_pixel(x, y, source.debug_color());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ppu::Mirroring;
+
+    // registers are picked up while the PPU runs, the vertical scroll only on the
+    // pre-render scanline at the end of the first frame
+    fn run_frame(ppu: &mut Ppu) {
+        while !ppu.tick(1) {}
+        while !ppu.tick(1) {}
+    }
+
+    #[test]
+    fn test_background_scrolling() {
+        let mut ppu = Ppu::new_empty_rom_with_mirroring(Mirroring::Vertical);
+        // both layers shown, no clipping in the left column
+        ppu.write_to_mask(0b0001_1110);
+        // tile 1 is solid color 1
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x21;
+        ppu.palette_table[5] = 0x16;
+        // top left tile of the second nametable, palette 1
+        ppu.vram[0x400] = 1;
+        ppu.vram[0x400 + 0x3c0] = 0b01;
+
+        ppu.write_to_scroll(252);
+        ppu.write_to_scroll(2);
+        run_frame(&mut ppu);
+        let mut frame = frame::Frame::new();
+        render(&ppu, &mut frame);
+        let at = |x: usize, y: usize| frame.indexed[y * frame::Frame::WIDTH + x];
+        // the tile spans x 4..12 and y 0..6 on screen
+        assert_eq!(at(3, 0), 0x0f);
+        assert_eq!(at(4, 0), 0x16);
+        assert_eq!(at(11, 5), 0x16);
+        assert_eq!(at(12, 0), 0x0f);
+        assert_eq!(at(4, 6), 0x0f);
+
+        // the first nametable selected by PPUCTRL wraps around to the second
+        ppu.write_to_ctrl(0b01);
+        run_frame(&mut ppu);
+        render(&ppu, &mut frame);
+        let at = |x: usize, y: usize| frame.indexed[y * frame::Frame::WIDTH + x];
+        assert_eq!(at(4, 0), 0x0f);
+    }
+
+    #[test]
+    fn test_nametable_select() {
+        // the second physical nametable: $2400 with vertical, $2800 with horizontal mirroring
+        for &(mirroring, ctrl) in [(Mirroring::Vertical, 0b01), (Mirroring::Horizontal, 0b10)].iter() {
+            let mut ppu = Ppu::new_empty_rom_with_mirroring(mirroring);
+            for i in 0..8 {
+                ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+            }
+            ppu.palette_table[1] = 0x21;
+            ppu.palette_table[13] = 0x16;
+            // bottom right tile (top right quadrant of its attribute byte), palette 3
+            ppu.vram[0x400 + 29 * 32 + 31] = 1;
+            ppu.vram[0x400 + 0x3ff] = 0b0000_1100;
+            let mut frame = frame::Frame::new();
+            ppu.write_to_mask(0b0000_1000);
+            ppu.write_to_ctrl(ctrl);
+            run_frame(&mut ppu);
+            render(&ppu, &mut frame);
+            assert_eq!(frame.indexed[239 * frame::Frame::WIDTH + 255], 0x16);
+
+            ppu.write_to_ctrl(0);
+            run_frame(&mut ppu);
+            render(&ppu, &mut frame);
+            assert_eq!(frame.indexed[239 * frame::Frame::WIDTH + 255], 0);
+        }
+    }
+
+    #[test]
+    fn test_fine_scroll_attributes() {
+        let mut ppu = Ppu::new_empty_rom_with_mirroring(Mirroring::Vertical);
+        // both layers shown, no clipping in the left column
+        ppu.write_to_mask(0b0001_1110);
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        for i in 0..4 {
+            ppu.palette_table[1 + i * 4] = 0x10 + i as u8;
+        }
+        // a row of solid tiles, each 2x2 tile area of both nametables with its own palette
+        for i in 0..32 {
+            ppu.vram[i] = 1;
+            ppu.vram[0x400 + i] = 1;
+        }
+        for i in 0..8 {
+            ppu.vram[0x3c0 + i] = 0b01_00;
+            ppu.vram[0x400 + 0x3c0 + i] = 0b11_10;
+        }
+
+        // tiles 1 and 2 are in different attribute quadrants
+        ppu.write_to_scroll(12);
+        ppu.write_to_scroll(3);
+        run_frame(&mut ppu);
+        let mut frame = frame::Frame::new();
+        render(&ppu, &mut frame);
+        let at = |x: usize, y: usize| frame.indexed[y * frame::Frame::WIDTH + x];
+        assert_eq!(at(3, 0), 0x10);
+        assert_eq!(at(4, 0), 0x11);
+        // only the last 5 lines of the row are visible
+        assert_eq!(at(4, 4), 0x11);
+        assert_eq!(at(4, 5), 0);
+        // the right edge shows the start of the second nametable
+        assert_eq!(at(243, 0), 0x11);
+        assert_eq!(at(244, 0), 0x12);
+        assert_eq!(at(255, 0), 0x12);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = Ppu::new_empty_rom();
+        // both layers shown, no clipping in the left column
+        ppu.write_to_mask(0b0001_1110);
+        // tile 1 is solid color 1, tile 2 solid color 2
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+            ppu.cartridge.borrow_mut().chr_write(32 + 8 + i, 0xff);
+        }
+        ppu.palette_table[1] = 0x21;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.palette_table[0x12] = 0x2a;
+        // background only on the left 8x8
+        ppu.vram[0] = 1;
+        // sprite 0 behind the background at (4, 0), sprite 1 in front at (0, 4)
+        ppu.oam_data[0..8].copy_from_slice(&[0, 1, 0b0010_0000, 4, 4, 2, 0, 0]);
+        for i in (8..256).step_by(4) {
+            ppu.oam_data[i] = 0xff;
+        }
+        run_frame(&mut ppu);
+        let mut frame = frame::Frame::new();
+        render(&ppu, &mut frame);
+        let at = |x: usize, y: usize| frame.indexed[y * frame::Frame::WIDTH + x];
+        // sprite 0 is hidden by the background, and still hides sprite 1 there
+        assert_eq!(at(5, 1), 0x21);
+        assert_eq!(at(5, 5), 0x21);
+        // in front of the transparent background
+        assert_eq!(at(9, 1), 0x16);
+        // sprite 1 shows where sprite 0 doesn't reach
+        assert_eq!(at(1, 5), 0x2a);
+    }
+
+    #[test]
+    fn test_sources() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.write_to_mask(0b0001_1110);
+        // tile 1 is solid color 1
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        // background only on the left 8x8
+        ppu.vram[0] = 1;
+        // sprite 0 at (32, 0), sprite 1 in front at (48, 0), sprite 2 behind at (4, 0)
+        ppu.oam_data[0..12].copy_from_slice(&[0, 1, 0, 32, 0, 1, 0, 48, 0, 1, 0b0010_0000, 4]);
+        for i in (12..256).step_by(4) {
+            ppu.oam_data[i] = 0xff;
+        }
+        run_frame(&mut ppu);
+        let mut last = LastFrame::new();
+        let mut frame = frame::Frame::new();
+        render_with(&ppu, &mut last, &mut frame);
+        let at = |x: usize, y: usize| last.sources.get(x, y).unwrap();
+        assert_eq!(at(100, 100), Source::Backdrop);
+        // the back sprite is hidden by the background, and shows past it
+        assert_eq!(at(5, 1), Source::Background);
+        assert_eq!(at(9, 1), Source::BackSprite);
+        assert_eq!(at(33, 1), Source::SpriteZero);
+        assert_eq!(at(49, 1), Source::FrontSprite);
+
+        show_sources(&last, &mut frame);
+        let rgb = |x: usize, y: usize| {
+            let base = (y * frame::Frame::WIDTH + x) * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+        };
+        assert_eq!(rgb(33, 1), Source::SpriteZero.debug_color());
+        assert_eq!(rgb(100, 100), Source::Backdrop.debug_color());
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis() {
+        let mut ppu = Ppu::new_empty_rom();
+        ppu.palette_table[0] = 0x21;
+        ppu.write_to_mask(0b0100_0001);
+        run_frame(&mut ppu);
+        let mut frame = frame::Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.indexed[0], 0x20);
+        assert_eq!(frame.emphasis[0], 0b010);
+    }
+
+    #[test]
+    fn test_left_clipping() {
+        let mut ppu = Ppu::new_empty_rom();
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        ppu.palette_table[1] = 0x21;
+        ppu.palette_table[0x11] = 0x16;
+        for i in 0..32 {
+            ppu.vram[32 + i] = 1;
+        }
+        // a sprite across the left edge, below the background row
+        ppu.oam_data[0..4].copy_from_slice(&[20, 1, 0, 4]);
+        for i in (4..256).step_by(4) {
+            ppu.oam_data[i] = 0xff;
+        }
+        let mut frame = frame::Frame::new();
+        let at = |frame: &frame::Frame, x: usize, y: usize| frame.indexed[y * frame::Frame::WIDTH + x];
+
+        ppu.write_to_mask(0b0001_1000);
+        run_frame(&mut ppu);
+        render(&ppu, &mut frame);
+        assert_eq!((at(&frame, 7, 8), at(&frame, 8, 8)), (0, 0x21));
+        assert_eq!((at(&frame, 7, 21), at(&frame, 8, 21)), (0, 0x16));
+
+        ppu.write_to_mask(0b0001_1110);
+        run_frame(&mut ppu);
+        render(&ppu, &mut frame);
+        assert_eq!(at(&frame, 7, 8), 0x21);
+        assert_eq!(at(&frame, 7, 21), 0x16);
+    }
+
+    #[test]
+    fn test_show_layers() {
+        let mut ppu = Ppu::new_empty_rom();
+        for i in 0..8 {
+            ppu.cartridge.borrow_mut().chr_write(16 + i, 0xff);
+        }
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x21;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.vram[1] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[20, 1, 0, 8]);
+        let mut frame = frame::Frame::new();
+        let at = |frame: &frame::Frame, x: usize, y: usize| frame.indexed[y * frame::Frame::WIDTH + x];
+
+        // rendering off shows the backdrop color
+        run_frame(&mut ppu);
+        render(&ppu, &mut frame);
+        assert_eq!((at(&frame, 8, 0), at(&frame, 8, 21)), (0x0f, 0x0f));
+
+        ppu.write_to_mask(0b0000_1000);
+        run_frame(&mut ppu);
+        render(&ppu, &mut frame);
+        assert_eq!((at(&frame, 8, 0), at(&frame, 8, 21)), (0x21, 0x0f));
+
+        ppu.write_to_mask(0b0001_0000);
+        run_frame(&mut ppu);
+        render(&ppu, &mut frame);
+        assert_eq!((at(&frame, 8, 0), at(&frame, 8, 21)), (0x0f, 0x16));
+    }
+}
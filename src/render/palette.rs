@@ -1,3 +1,6 @@
+use ines::TvSystem;
+use std::f32::consts::PI;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALETTE: [(u8,u8,u8); 64] = [
@@ -14,4 +17,196 @@ pub static SYSTEM_PALETTE: [(u8,u8,u8); 64] = [
     (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), 
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+// composite video levels (volts) of the 4 lumas, below and above the chroma wave
+const SIGNAL_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+const SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+const BLACK: f32 = 0.312;
+const WHITE: f32 = 1.100;
+
+// how a TV decodes the PPU's signal differently per region
+struct Decoder {
+    // rotation of every hue, degrees
+    hue: f32,
+    saturation: f32,
+}
+
+impl Decoder {
+    fn new(tv_system: &TvSystem) -> Self {
+        match tv_system {
+            TvSystem::Ntsc => Decoder { hue: 0.0, saturation: 1.0 },
+            // the 2C07's color phases sit about 15 degrees off the 2C02's, and PAL TVs
+            // average the phase error of alternate lines out into lower saturation
+            TvSystem::Pal => Decoder { hue: -15.0, saturation: (15.0f32).to_radians().cos() },
+        }
+    }
+}
+
+// generate the palette by decoding the PPU's composite signal for each color:
+// 12 samples per color subcarrier cycle of a square wave between the luma's
+// low and high levels, phase shifted by the hue
+pub fn generate(tv_system: &TvSystem) -> [(u8, u8, u8); 64] {
+    let decoder = Decoder::new(tv_system);
+    let mut palette = [(0, 0, 0); 64];
+    for (color, rgb) in palette.iter_mut().enumerate() {
+        let (hue, luma) = (color & 0x0f, color >> 4);
+        let level = |phase: usize| {
+            let volts = match hue {
+                0x0 => SIGNAL_HIGH[luma],
+                0xd => SIGNAL_LOW[luma],
+                0xe | 0xf => BLACK,
+                _ if (phase + hue) % 12 < 6 => SIGNAL_HIGH[luma],
+                _ => SIGNAL_LOW[luma],
+            };
+            (volts - BLACK) / (WHITE - BLACK)
+        };
+        let (mut y, mut u, mut v) = (0.0, 0.0, 0.0);
+        for phase in 0..12 {
+            let angle = -2.0 * PI * phase as f32 / 12.0 + decoder.hue.to_radians();
+            y += level(phase) / 12.0;
+            u += level(phase) * angle.cos() / 6.0 * decoder.saturation;
+            v += level(phase) * angle.sin() / 6.0 * decoder.saturation;
+        }
+        let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        *rgb = (
+            channel(y + 1.140 * v),
+            channel(y - 0.395 * u - 0.581 * v),
+            channel(y + 2.032 * u),
+        );
+    }
+    palette
+}
+
+// a .pal file: 64 RGB triples, any further (emphasis) entries are ignored
+pub fn load(data: &[u8]) -> Result<[(u8, u8, u8); 64], String> {
+    if data.len() < 64 * 3 {
+        return Err(format!("a palette file has at least 192 bytes, got {}", data.len()));
+    }
+    let mut palette = [(0, 0, 0); 64];
+    for (rgb, bytes) in palette.iter_mut().zip(data.chunks(3)) {
+        *rgb = (bytes[0], bytes[1], bytes[2]);
+    }
+    Ok(palette)
+}
+
+// how much each PPUMASK emphasis bit darkens the other two colors
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+// the palette under each of the 8 emphasis settings (bit 0: red, 1: green, 2: blue)
+pub fn emphasized(palette: &[(u8, u8, u8); 64]) -> Vec<[(u8, u8, u8); 64]> {
+    (0..8u8).map(|emphasis| {
+        let attenuate = |c: u8, bit: u8| {
+            let others = (emphasis & !bit).count_ones() as i32;
+            (c as f32 * EMPHASIS_ATTENUATION.powi(others)).round() as u8
+        };
+        let mut table = *palette;
+        for rgb in table.iter_mut() {
+            *rgb = (attenuate(rgb.0, 0b001), attenuate(rgb.1, 0b010), attenuate(rgb.2, 0b100));
+        }
+        table
+    }).collect()
+}
+
+// picture controls applied when palette indices are converted to RGB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorAdjust {
+    // > 1 brightens mid tones
+    pub gamma: f32,
+    pub brightness: f32,
+    // 0 is greyscale
+    pub saturation: f32,
+}
+
+impl ColorAdjust {
+    pub fn new() -> Self {
+        ColorAdjust {
+            gamma: 1.0,
+            brightness: 1.0,
+            saturation: 1.0,
+        }
+    }
+
+    pub fn apply(&self, rgb: (u8, u8, u8)) -> (u8, u8, u8) {
+        let (r, g, b) = (rgb.0 as f32 / 255.0, rgb.1 as f32 / 255.0, rgb.2 as f32 / 255.0);
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        let adjust = |c: f32| {
+            let c = (luma + (c - luma) * self.saturation) * self.brightness;
+            (c.clamp(0.0, 1.0).powf(1.0 / self.gamma) * 255.0).round() as u8
+        };
+        (adjust(r), adjust(g), adjust(b))
+    }
+
+    // the palette with the adjustment applied, computed once per change
+    pub fn table(&self, palette: &[(u8, u8, u8); 64]) -> [(u8, u8, u8); 64] {
+        let mut table = *palette;
+        for rgb in table.iter_mut() {
+            *rgb = self.apply(*rgb);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let ntsc = generate(&TvSystem::Ntsc);
+        assert_eq!(ntsc[0x20], (0xff, 0xff, 0xff));
+        assert_eq!(ntsc[0x0f], (0x00, 0x00, 0x00));
+        // greys have no chroma
+        let (r, g, b) = ntsc[0x10];
+        assert!(r == g && g == b);
+        let (r, g, b) = ntsc[0x16];
+        assert!(r > g && r > b);
+        let (r, g, b) = ntsc[0x1a];
+        assert!(g > r && g > b);
+        let (r, g, b) = ntsc[0x12];
+        assert!(b > r && b > g);
+
+        let pal = generate(&TvSystem::Pal);
+        assert_eq!(pal[0x10], ntsc[0x10]);
+        assert_ne!(pal[0x16], ntsc[0x16]);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut data = vec![0; 64 * 3];
+        data[3..6].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(load(&data).unwrap()[1], (1, 2, 3));
+        // with the emphasis variants appended
+        data.resize(8 * 64 * 3, 0);
+        assert_eq!(load(&data).unwrap()[1], (1, 2, 3));
+        assert!(load(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn test_emphasis() {
+        let palettes = emphasized(&SYSTEM_PALETTE);
+        assert_eq!(palettes[0][..], SYSTEM_PALETTE[..]);
+        let white = SYSTEM_PALETTE[0x30];
+        let dimmed = (white.1 as f32 * EMPHASIS_ATTENUATION).round() as u8;
+        assert_eq!(palettes[0b001][0x30], (white.0, dimmed, dimmed));
+        // green is darkened by both
+        let (r, g, b) = palettes[0b101][0x30];
+        assert_eq!((r, b), (dimmed, dimmed));
+        assert!(g < dimmed);
+    }
+
+    #[test]
+    fn test_color_adjust() {
+        assert_eq!(ColorAdjust::new().table(&SYSTEM_PALETTE)[..], SYSTEM_PALETTE[..]);
+
+        let grey = ColorAdjust { saturation: 0.0, ..ColorAdjust::new() };
+        let (r, g, b) = grey.apply((0xff, 0x00, 0x00));
+        assert!(r == g && g == b);
+
+        let bright = ColorAdjust { brightness: 2.0, ..ColorAdjust::new() };
+        assert_eq!(bright.apply((0x40, 0x80, 0xc0)), (0x80, 0xff, 0xff));
+
+        let gamma = ColorAdjust { gamma: 2.0, ..ColorAdjust::new() };
+        let (r, _, _) = gamma.apply((0x40, 0x40, 0x40));
+        assert_eq!(r, 0x80);
+    }
+}
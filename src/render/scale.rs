@@ -0,0 +1,73 @@
+use render::frame::Frame;
+
+// filters for frames upscaled by the core, for frontends that can't scale quickly themselves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // every pixel becomes a factor x factor block
+    Nearest,
+    // like Nearest with the last row of each block darkened, as on a CRT
+    Scanlines,
+}
+
+impl Filter {
+    pub fn parse(name: &str) -> Option<Filter> {
+        match name {
+            "nearest" => Some(Filter::Nearest),
+            "scanlines" => Some(Filter::Scanlines),
+            _ => None,
+        }
+    }
+}
+
+const SCANLINE_BRIGHTNESS: u16 = 128;
+
+// RGB24 of the frame at factor times its size (256 * factor x 240 * factor)
+pub fn upscale(frame: &Frame, factor: usize, filter: Filter) -> Vec<u8> {
+    let width = Frame::WIDTH * factor;
+    let mut data = Vec::with_capacity(width * Frame::HIGHT * factor * 3);
+    for y in 0..Frame::HIGHT {
+        let line = &frame.data[y * Frame::WIDTH * 3..(y + 1) * Frame::WIDTH * 3];
+        for row in 0..factor {
+            let dark = filter == Filter::Scanlines && factor > 1 && row == factor - 1;
+            for rgb in line.chunks(3) {
+                for _ in 0..factor {
+                    if dark {
+                        data.extend(rgb.iter().map(|&c| (c as u16 * SCANLINE_BRIGHTNESS / 255) as u8));
+                    } else {
+                        data.extend_from_slice(rgb);
+                    }
+                }
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upscale() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (0xff, 0x80, 0x00));
+        let pixel = |data: &[u8], factor: usize, x: usize, y: usize| {
+            let base = (y * Frame::WIDTH * factor + x) * 3;
+            (data[base], data[base + 1], data[base + 2])
+        };
+
+        let native = upscale(&frame, 1, Filter::Scanlines);
+        assert_eq!(native, frame.data);
+
+        let nearest = upscale(&frame, 2, Filter::Nearest);
+        assert_eq!(nearest.len(), frame.data.len() * 4);
+        for &(x, y) in [(2, 0), (3, 0), (2, 1), (3, 1)].iter() {
+            assert_eq!(pixel(&nearest, 2, x, y), (0xff, 0x80, 0x00));
+        }
+        assert_eq!(pixel(&nearest, 2, 4, 0), pixel(&frame.data, 1, 2, 0));
+
+        let scanlines = upscale(&frame, 3, Filter::Scanlines);
+        assert_eq!(pixel(&scanlines, 3, 3, 1), (0xff, 0x80, 0x00));
+        assert_eq!(pixel(&scanlines, 3, 3, 2), (0x80, 0x40, 0x00));
+    }
+}
@@ -0,0 +1,48 @@
+// per-pixel buffer the size of the screen (or any other), for intermediate results of a frame
+#[derive(Debug, Clone)]
+pub struct Surface<T> {
+    width: usize,
+    height: usize,
+    data: Vec<T>,
+}
+
+impl<T: Copy> Surface<T> {
+    pub fn new(width: usize, height: usize, fill: T) -> Self {
+        Surface {
+            width,
+            height,
+            data: vec![fill; width * height],
+        }
+    }
+
+    // None outside the surface
+    pub fn get(&self, x: usize, y: usize) -> Option<T> {
+        if x < self.width && y < self.height {
+            Some(self.data[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    // writes outside the surface are ignored
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        if x < self.width && y < self.height {
+            self.data[y * self.width + x] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_surface() {
+        let mut surface = Surface::new(4, 2, false);
+        surface.set(3, 1, true);
+        surface.set(4, 0, true);
+        assert_eq!(surface.get(3, 1), Some(true));
+        assert_eq!(surface.get(0, 1), Some(false));
+        assert_eq!(surface.get(4, 0), None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{Result, Write};
+use ines::{Rom, TvSystem};
+use ppu::Mirroring;
+use cartridge;
+
+#[derive(Debug, PartialEq)]
+pub enum Compatibility {
+    // nothing known to be missing
+    Playable,
+    // runs, but some feature of the cartridge is not emulated
+    Partial,
+    // will not boot
+    Unsupported,
+}
+
+fn mapper_name(mapper: u8) -> &'static str {
+    match mapper {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        5 => "MMC5",
+        7 => "AxROM",
+        9 => "MMC2",
+        10 => "MMC4",
+        11 => "Color Dreams",
+        19 => "Namco 163",
+        24 | 26 => "VRC6",
+        66 => "GxROM",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica",
+        85 => "VRC7",
+        _ => "unknown",
+    }
+}
+
+// cartridges with their own sound chip
+fn expansion_audio(mapper: u8) -> Option<&'static str> {
+    match mapper {
+        5 => Some("MMC5"),
+        19 => Some("Namco 163"),
+        24 | 26 => Some("VRC6"),
+        69 => Some("Sunsoft 5B"),
+        85 => Some("VRC7"),
+        _ => None,
+    }
+}
+
+pub fn compatibility(rom: &Rom) -> Compatibility {
+    if !cartridge::is_supported(rom.mapper) || rom.mirroring == Mirroring::FourScreen {
+        return Compatibility::Unsupported;
+    }
+    if rom.tv_system == TvSystem::Pal || expansion_audio(rom.mapper).is_some() {
+        return Compatibility::Partial;
+    }
+    Compatibility::Playable
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b { "yes" } else { "no" }
+}
+
+pub fn write_report<W: Write>(out: &mut W, rom: &Rom) -> Result<()> {
+    let mapper_supported = cartridge::is_supported(rom.mapper);
+    writeln!(out, "mapper:          {} ({}), implemented: {}",
+        rom.mapper, mapper_name(rom.mapper), yes_no(mapper_supported))?;
+    writeln!(out, "PRG ROM:         {} KiB", rom.prg_rom.len() / 1024)?;
+    writeln!(out, "{}:         {} KiB", if rom.chr_ram { "CHR RAM" } else { "CHR ROM" }, rom.chr_rom.len() / 1024)?;
+    writeln!(out, "mirroring:       {:?}{}", rom.mirroring,
+        if rom.mirroring == Mirroring::FourScreen { " (not implemented)" } else { "" })?;
+    match expansion_audio(rom.mapper) {
+        Some(chip) => writeln!(out, "expansion audio: {} (not implemented)", chip)?,
+        None => writeln!(out, "expansion audio: none")?,
+    }
+    writeln!(out, "battery:         {}{}", yes_no(rom.battery),
+        if rom.battery { " (saved to .sav on exit)" } else { "" })?;
+    writeln!(out, "region:          {:?}{}", rom.tv_system,
+        if rom.tv_system == TvSystem::Pal { " (runs with NTSC timing)" } else { "" })?;
+    writeln!(out, "compatibility:   {:?}", compatibility(rom))
+}
+
+// nes-emu report <file path>
+pub fn run(args: &[String]) -> ! {
+    let path = match args.get(2) {
+        Some(path) => path,
+        None => {
+            println!("usage: nes-emu report <file path>");
+            std::process::exit(0);
+        }
+    };
+    let raw = std::fs::read(path).unwrap();
+    match Rom::analyze_raw(&raw) {
+        Ok(rom) => write_report(&mut std::io::stdout(), &rom).unwrap(),
+        Err(msg) => {
+            println!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ines::test;
+
+    #[test]
+    fn test_report() {
+        let mut rom = test::create_rom();
+        rom.mapper = 0;
+        assert_eq!(compatibility(&rom), Compatibility::Playable);
+        rom.battery = true;
+        assert_eq!(compatibility(&rom), Compatibility::Playable);
+        rom.tv_system = TvSystem::Pal;
+        assert_eq!(compatibility(&rom), Compatibility::Partial);
+        rom.mapper = 4;
+        assert_eq!(compatibility(&rom), Compatibility::Unsupported);
+
+        let mut out = vec![];
+        write_report(&mut out, &rom).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("mapper:          4 (MMC3), implemented: no"));
+        assert!(out.contains("compatibility:   Unsupported"));
+    }
+}
@@ -0,0 +1,56 @@
+// plain text dump of the whole machine, written when the fuzzer finds a crash or jam
+// so a bug report shows what the game saw without rerunning it
+
+use std::io::{Result, Write};
+use cpu::Cpu;
+
+// 16 bytes per line, addresses counted from base
+pub fn write_hex<W: Write>(out: &mut W, name: &str, base: u16, data: &[u8]) -> Result<()> {
+    writeln!(out, "{}:", name)?;
+    for (i, row) in data.chunks(16).enumerate() {
+        let bytes: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "  {:04X}: {}", base as usize + i * 16, bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+pub fn write_state<W: Write>(cpu: &Cpu, out: &mut W) -> Result<()> {
+    writeln!(out, "cpu: PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}{}",
+        cpu.pc, cpu.a, cpu.x, cpu.y, cpu.stat.bits(), cpu.sp,
+        if cpu.is_jammed() { " (jammed)" } else { "" })?;
+    cpu.bus.write_state(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use apu::Apu;
+    use controller::Joypad;
+    use memory::{Bus, Mem};
+    use ppu::Ppu;
+    use ines::test;
+
+    #[test]
+    fn test_write_hex() {
+        let mut out = vec![];
+        write_hex(&mut out, "ram", 0x10, &(0..18).collect::<Vec<u8>>()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "ram:\n  0010: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n  0020: 10 11\n");
+    }
+
+    #[test]
+    fn test_write_state() {
+        let bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        cpu.mem_write(0x0012, 0xab);
+        cpu.pc = 0x8123;
+        let mut out = vec![];
+        write_state(&cpu, &mut out).unwrap();
+        let state = String::from_utf8(out).unwrap();
+        assert!(state.starts_with("cpu: PC:8123 A:00 X:00 Y:00"));
+        assert!(state.contains("\n  0010: 00 00 AB 00"));
+        for section in &["ppu:", "ram:", "vram:", "oam:", "palette:"] {
+            assert!(state.contains(section), "no {} in the dump", section);
+        }
+    }
+}
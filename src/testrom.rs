@@ -0,0 +1,145 @@
+// result protocol of blargg's test ROMs (cpu_interrupts_v2, instr_test, ...)
+// $6001-$6003 hold DE B0 61 once the ROM has started, $6000 holds the status
+// and a zero terminated message is at $6004
+
+use memory::Bus;
+use ines;
+use cartridge;
+use memory;
+use cpu;
+use ppu;
+use apu;
+use controller;
+
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Running,
+    // the ROM wants the reset button pressed, after at least 100ms
+    ResetRequested,
+    Passed,
+    // result code, 2 and up identify the failed subtest
+    Failed(u8),
+}
+
+// None until the ROM writes the signature
+// memory is peeked, the checks don't disturb the ROM
+pub fn status(bus: &Bus) -> Option<Status> {
+    let signature = [bus.peek(0x6001), bus.peek(0x6002), bus.peek(0x6003)];
+    if signature != SIGNATURE {
+        return None;
+    }
+    Some(match bus.peek(0x6000) {
+        0x80 => Status::Running,
+        0x81 => Status::ResetRequested,
+        0x00 => Status::Passed,
+        code => Status::Failed(code),
+    })
+}
+
+pub fn message(bus: &Bus) -> String {
+    let mut text = vec![];
+    for addr in 0x6004..0x8000 {
+        match bus.peek(addr) {
+            0 => break,
+            c => text.push(c),
+        }
+    }
+    String::from_utf8_lossy(&text).trim().to_string()
+}
+
+// nes-emu test-rom <file path>..., one line per ROM
+pub fn run(args: &[String]) -> ! {
+    let paths = &args[2..];
+    if paths.is_empty() {
+        println!("usage: nes-emu test-rom <file path>...");
+        std::process::exit(0);
+    }
+    // the slowest interrupt tests finish in a few seconds
+    let frame_limit = 60 * 30;
+    let mut failures = 0;
+    for path in paths {
+        let raw = std::fs::read(path).unwrap();
+        let rom = ines::Rom::analyze_raw(&raw).unwrap();
+        if !cartridge::is_supported(rom.mapper) {
+            println!("{}: mapper {} is not supported", path, rom.mapper);
+            failures += 1;
+            continue;
+        }
+        let bus = memory::Bus::new(rom, |_ppu: &ppu::Ppu, _apu: &mut apu::Apu, _joypads: &mut [controller::Joypad; 2]| {});
+        let mut cpu = cpu::Cpu::new(bus);
+        cpu.reset();
+        let mut result = None;
+        let mut reset_at = None;
+        let mut checked_frame = 0;
+        // a panic (e.g. an unimplemented opcode) fails the ROM instead of the whole run
+        let run = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cpu.run_with_callback(|cpu| {
+                let frame = cpu.bus.frame_count();
+                if frame == checked_frame {
+                    return;
+                }
+                checked_frame = frame;
+                match status(&cpu.bus) {
+                    Some(Status::Running) | None => (),
+                    Some(Status::ResetRequested) => {
+                        // hold reset for 6 frames (100ms)
+                        let at = *reset_at.get_or_insert(frame + 6);
+                        if frame >= at {
+                            reset_at = None;
+                            cpu.reset();
+                        }
+                    },
+                    Some(status) => {
+                        result = Some((status, message(&cpu.bus)));
+                        cpu.halt();
+                    },
+                }
+                if frame >= frame_limit {
+                    cpu.halt();
+                }
+            });
+        }));
+        let line = match (run, result) {
+            (Err(_), _) => String::from("crashed"),
+            (Ok(()), Some((Status::Passed, _))) => String::from("passed"),
+            (Ok(()), Some((status, message))) => format!("{:?}: {}", status, message.replace('\n', " ")),
+            (Ok(()), None) if cpu.is_jammed() => format!("jammed at ${:04X}", cpu.pc),
+            (Ok(()), None) => format!("no result after {} frames", frame_limit),
+        };
+        if line != "passed" {
+            failures += 1;
+        }
+        println!("{}: {}", path, line);
+    }
+    println!("{} of {} passed", paths.len() - failures, paths.len());
+    std::process::exit(if failures == 0 { 0 } else { 1 });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::Mem;
+    use ppu::Ppu;
+    use apu::Apu;
+    use controller::Joypad;
+    use ines::test;
+
+    #[test]
+    fn test_status() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        assert_eq!(status(&bus), None);
+        for (i, b) in SIGNATURE.iter().enumerate() {
+            bus.mem_write(0x6001 + i as u16, *b);
+        }
+        bus.mem_write(0x6000, 0x80);
+        assert_eq!(status(&bus), Some(Status::Running));
+        bus.mem_write(0x6000, 0x03);
+        assert_eq!(status(&bus), Some(Status::Failed(3)));
+        for (i, c) in b"\n3) BRK\n".iter().enumerate() {
+            bus.mem_write(0x6004 + i as u16, *c);
+        }
+        assert_eq!(message(&bus), "3) BRK");
+    }
+}
@@ -30,4 +30,22 @@ pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
         }
     }
     frame
-}
\ No newline at end of file
+}
+
+// all 256 tiles of a bank laid out 16x16 with 1 pixel gaps
+pub fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
+    let mut frame = Frame::new();
+    for i in 0..256 {
+        let tile_frame = show_tile(chr_rom, bank, i);
+        let base_x = (i % 16) * 9;
+        let base_y = (i / 16) * 9;
+        for y in 0..8 {
+            for x in 0..8 {
+                let pos = (y * Frame::WIDTH + x) * 3;
+                let rgb = (tile_frame.data[pos], tile_frame.data[pos + 1], tile_frame.data[pos + 2]);
+                frame.set_pixel(base_x + x, base_y + y, rgb);
+            }
+        }
+    }
+    frame
+}
@@ -1,35 +1,33 @@
-use std::collections::HashMap;
 use cpu::Cpu;
 use cpu::AddressingMode;
-use memory::Mem;
+use cpu::addressing;
 use instructions;
 
-pub fn trace(cpu: &mut Cpu) -> String {
-    let ref insts: HashMap<u8, &'static instructions::Instruction> = *instructions::INSTRUCTION_MAP;
-    let code = cpu.mem_read(cpu.pc);
-    let cur_inst = insts.get(&code).unwrap();
+// memory is only peeked, so tracing doesn't change what the game reads
+pub fn trace(cpu: &Cpu) -> String {
+    let code = cpu.bus.peek(cpu.pc);
+    let cur_inst = instructions::INSTRUCTION_TABLE[code as usize].unwrap();
 
     let inst_begin = cpu.pc;
     let mut hex_dump = vec![];
     hex_dump.push(code);
 
     let (mem_addr, stored_value) = match cur_inst.mode {
-        AddressingMode::Immediate | AddressingMode::Implied | AddressingMode::Relative => (0,0),
+        AddressingMode::Immediate | AddressingMode::Accumulator | AddressingMode::Implied | AddressingMode::Relative => (0,0),
         _ => {
-            cpu.pc += 1;
-            let addr = cpu.get_operand_address(&cur_inst.mode);
-            cpu.pc -= 1;
-            (addr, cpu.mem_read(addr))
+            let operand = cpu.pc.wrapping_add(1);
+            let (addr, _) = addressing::operand_address(&cur_inst.mode, operand, cpu.x, cpu.y, |addr| cpu.bus.peek(addr));
+            (addr, cpu.bus.peek(addr))
         }
     };
 
     let tmp = match cur_inst.len {
-        1 => match cur_inst.opcode {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
+        1 => match cur_inst.mode {
+            AddressingMode::Accumulator => format!("A "),
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(inst_begin + 1);
+            let address: u8 = cpu.bus.peek(inst_begin + 1);
             hex_dump.push(address);
 
             match cur_inst.mode {
@@ -57,7 +55,7 @@ pub fn trace(cpu: &mut Cpu) -> String {
                     mem_addr,
                     stored_value
                 ),
-                AddressingMode::Implied | AddressingMode::Relative => {
+                AddressingMode::Relative => {
                     // assuming local jumps: BNE, BVS, etc....
                     let address: usize =
                         (inst_begin as usize + 2).wrapping_add((address as i8) as usize);
@@ -71,31 +69,15 @@ pub fn trace(cpu: &mut Cpu) -> String {
             }
         },
         3 => {
-            let address_low = cpu.mem_read(inst_begin + 1);
-            let address_high = cpu.mem_read(inst_begin + 2);
+            let address_low = cpu.bus.peek(inst_begin + 1);
+            let address_high = cpu.bus.peek(inst_begin + 2);
             hex_dump.push(address_low);
             hex_dump.push(address_high);
 
-            let address = cpu.mem_read_u16(inst_begin + 1);
+            let address = cpu.bus.peek_u16(inst_begin + 1);
 
             match cur_inst.mode {
-                AddressingMode::Implied | AddressingMode::Relative => {
-                    if cur_inst.opcode == 0x6c {
-                        //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-
-                        // let jmp_addr = cpu.mem_read_u16(address);
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
-                }
+                AddressingMode::Indirect => format!("(${:04x}) = {:04x}", address, mem_addr),
                 AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
                 AddressingMode::AbsoluteX => format!(
                     "${:04x},X @ {:04x} = {:02x}",
@@ -130,16 +112,106 @@ pub fn trace(cpu: &mut Cpu) -> String {
         .to_ascii_uppercase()
 }
 
+// one JSON object per instruction, for tools that don't want to parse the nestest format
+// e.g. {"pc":100,"opcode":162,"operands":[1],"mnemonic":"LDX","a":1,...,"cycles":7,"scanline":0,"dot":21}
+pub fn trace_json(cpu: &Cpu) -> String {
+    let code = cpu.bus.peek(cpu.pc);
+    let cur_inst = instructions::INSTRUCTION_TABLE[code as usize].unwrap();
+    let operands = (1..cur_inst.len as u16)
+        .map(|i| cpu.bus.peek(cpu.pc.wrapping_add(i)).to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let (scanline, dot) = cpu.bus.ppu_position();
+    format!(
+        "{{\"pc\":{},\"opcode\":{},\"operands\":[{}],\"mnemonic\":\"{}\",\"a\":{},\"x\":{},\"y\":{},\"p\":{},\"sp\":{},\"cycles\":{},\"scanline\":{},\"dot\":{}}}",
+        cpu.pc, code, operands, cur_inst.mnemonic, cpu.a, cpu.x, cpu.y, cpu.stat.bits(), cpu.sp,
+        cpu.bus.cycles(), scanline, dot,
+    )
+}
+
+// register state picked out of a trace line
+// understands this emulator's output, nestest.log, Mesen and FCEUX style lines
+#[derive(Debug, PartialEq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub a: Option<u8>,
+    pub x: Option<u8>,
+    pub y: Option<u8>,
+    pub sp: Option<u8>,
+    // FCEUX prints flags as letters (nvubdIzc), which is not compared
+    pub p: Option<u8>,
+}
+
+impl TraceState {
+    // the registers of a running CPU, without reading memory
+    pub fn of(cpu: &Cpu) -> TraceState {
+        TraceState {
+            pc: cpu.pc,
+            a: Some(cpu.a),
+            x: Some(cpu.x),
+            y: Some(cpu.y),
+            sp: Some(cpu.sp),
+            p: Some(cpu.stat.bits()),
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<TraceState> {
+        let pc: String = line.trim_start_matches('$').chars().take(4).collect();
+        let mut state = TraceState {
+            pc: u16::from_str_radix(&pc, 16).ok()?,
+            a: None,
+            x: None,
+            y: None,
+            sp: None,
+            p: None,
+        };
+        for token in line.split_whitespace() {
+            let mut kv = token.splitn(2, ':');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, u8::from_str_radix(value, 16).ok()),
+                _ => continue,
+            };
+            match key {
+                "A" => state.a = value,
+                "X" => state.x = value,
+                "Y" => state.y = value,
+                "SP" | "S" => state.sp = value,
+                "P" => state.p = value,
+                _ => (),
+            }
+        }
+        Some(state)
+    }
+
+    // fields missing on either side are not compared
+    pub fn matches(&self, other: &TraceState) -> bool {
+        fn same(a: Option<u8>, b: Option<u8>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+        self.pc == other.pc
+            && same(self.a, other.a)
+            && same(self.x, other.x)
+            && same(self.y, other.y)
+            && same(self.sp, other.sp)
+            && same(self.p, other.p)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use memory::Bus;
+    use memory::{Bus, Mem};
     use ppu::Ppu;
+    use apu::Apu;
+    use controller::Joypad;
     use ines::test;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test::create_rom(), |ppu: &Ppu| {});
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -152,7 +224,7 @@ mod test {
         cpu.x = 2;
         cpu.y = 3;
         let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
+        cpu.run_until_brk(|cpu| {
             println!("{}", trace(cpu));
             result.push(trace(cpu));
         });
@@ -170,9 +242,34 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_trace_has_no_side_effects() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        // LDA $2002
+        bus.mem_write(100, 0xad);
+        bus.mem_write(101, 0x02);
+        bus.mem_write(102, 0x20);
+        while bus.ppu_position().0 != 241 {
+            bus.tick(1);
+        }
+        let mut cpu = Cpu::new(bus);
+        cpu.pc = 100;
+        let mut result: Vec<String> = vec![];
+        cpu.run_until_brk(|cpu| {
+            trace_json(cpu);
+            result.push(trace(cpu));
+        });
+        assert_eq!(
+            "0064  AD 02 20  LDA $2002 = 80                  A:00 X:00 Y:00 P:24 SP:FD",
+            result[0]
+        );
+        // the game still sees vblank
+        assert_eq!(cpu.a, 0x80);
+    }
+
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test::create_rom(), |ppu: &Ppu| {});
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -188,7 +285,7 @@ mod test {
         cpu.pc = 0x64;
         cpu.y = 0;
         let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
+        cpu.run_until_brk(|cpu| {
             result.push(trace(cpu));
         });
         assert_eq!(
@@ -196,4 +293,117 @@ mod test {
             result[0]
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_format_accumulator_and_indirect() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        // ASL A; JMP ($02ff)
+        for (i, &byte) in [0x0a, 0x6c, 0xff, 0x02].iter().enumerate() {
+            bus.mem_write(100 + i as u16, byte);
+        }
+        // the high byte comes from $0200, not $0300
+        bus.mem_write(0x02ff, 0x00);
+        bus.mem_write(0x0200, 0x03);
+        bus.mem_write(0x0300, 0x00);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.pc = 100;
+        let mut result: Vec<String> = vec![];
+        cpu.run_until_brk(|cpu| {
+            result.push(trace(cpu));
+        });
+        assert_eq!(
+            "0064  0A        ASL A                           A:00 X:00 Y:00 P:24 SP:FD",
+            result[0]
+        );
+        assert_eq!(
+            "0065  6C FF 02  JMP ($02FF) = 0300              A:00 X:00 Y:00 P:26 SP:FD",
+            result[1]
+        );
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn test_format_unofficial() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        // *NOP $10; *LAX $10; *NOP #$01
+        for (i, &byte) in [0x04, 0x10, 0xa7, 0x10, 0x80, 0x01, 0x00].iter().enumerate() {
+            bus.mem_write(100 + i as u16, byte);
+        }
+        bus.mem_write(0x10, 0x55);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.pc = 100;
+        let mut result: Vec<String> = vec![];
+        cpu.run_until_brk(|cpu| {
+            result.push(trace(cpu));
+        });
+        assert_eq!(
+            "0064  04 10    *NOP $10 = 55                    A:00 X:00 Y:00 P:24 SP:FD",
+            result[0]
+        );
+        assert_eq!(
+            "0066  A7 10    *LAX $10 = 55                    A:00 X:00 Y:00 P:24 SP:FD",
+            result[1]
+        );
+        assert_eq!(
+            "0068  80 01    *NOP #$01                        A:55 X:55 Y:00 P:24 SP:FD",
+            result[2]
+        );
+    }
+
+    #[test]
+    fn test_trace_json() {
+        let mut bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        bus.mem_write(100, 0xa2);
+        bus.mem_write(101, 0x01);
+        bus.mem_write(102, 0xca);
+        bus.mem_write(103, 0x00);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.pc = 100;
+        cpu.a = 1;
+        let mut result: Vec<String> = vec![];
+        cpu.run_until_brk(|cpu| {
+            result.push(trace_json(cpu));
+        });
+        assert_eq!(
+            "{\"pc\":100,\"opcode\":162,\"operands\":[1],\"mnemonic\":\"LDX\",\"a\":1,\"x\":0,\"y\":0,\"p\":36,\"sp\":253,\"cycles\":0,\"scanline\":0,\"dot\":0}",
+            result[0]
+        );
+        assert_eq!(
+            "{\"pc\":102,\"opcode\":202,\"operands\":[],\"mnemonic\":\"DEX\",\"a\":1,\"x\":1,\"y\":0,\"p\":36,\"sp\":253,\"cycles\":2,\"scanline\":0,\"dot\":6}",
+            result[1]
+        );
+    }
+
+    #[test]
+    fn test_compare_trace_lines() {
+        let ours = TraceState::parse(
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD").unwrap();
+        let nestest = TraceState::parse(
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10").unwrap();
+        let fceux = TraceState::parse(
+            "$C5F5:A2 00     LDX #$00                A:00 X:00 Y:00 S:FD P:nvUbdIzc").unwrap();
+        assert!(ours.matches(&nestest));
+        assert!(ours.matches(&fceux));
+        assert_eq!(fceux.p, None);
+
+        let diverged = TraceState::parse(
+            "C5F5  A2 00     LDX #$00                        A:00 X:01 Y:00 P:24 SP:FD").unwrap();
+        assert!(!ours.matches(&diverged));
+    }
+
+    #[test]
+    fn test_state_of_cpu() {
+        let bus = Bus::new(test::create_rom(), |_ppu: &Ppu, _apu: &mut Apu, _joypads: &mut [Joypad; 2]| {});
+        let mut cpu = Cpu::new(bus);
+        cpu.pc = 0xc5f5;
+        cpu.x = 1;
+        let state = TraceState::of(&cpu);
+        assert_eq!(Some(&state), TraceState::parse(&trace(&cpu)).as_ref());
+        let nestest = TraceState::parse(
+            "C5F5  A2 00     LDX #$00                        A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10").unwrap();
+        assert!(state.matches(&nestest));
+    }
+}
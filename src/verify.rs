@@ -0,0 +1,220 @@
+// replaying an input movie and comparing a frame against a reference image
+// movies use the fuzz log format: one "frame,buttons" line per frame, buttons as 8 binary
+// digits in joypad bit order, applied after that frame is shown
+
+use std::io::{self, Write};
+use controller::JoypadButton;
+use render::frame::Frame;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::rc::Rc;
+use ines;
+use cartridge;
+use memory;
+use cpu;
+use ppu;
+use apu;
+use controller;
+use render;
+
+pub struct Movie {
+    inputs: Vec<(u64, JoypadButton)>,
+}
+
+impl Movie {
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut inputs = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(2, ',');
+            let frame = fields.next().and_then(|f| f.trim().parse().ok());
+            let buttons = fields.next().and_then(|b| u8::from_str_radix(b.trim(), 2).ok());
+            match (frame, buttons) {
+                (Some(frame), Some(buttons)) => inputs.push((frame, JoypadButton::from_bits_truncate(buttons))),
+                _ => return Err(format!("line {}: expected FRAME,BUTTONS: `{}`", n + 1, line)),
+            }
+        }
+        Ok(Movie { inputs })
+    }
+
+    // buttons held after the given frame, None if the movie doesn't change them
+    pub fn buttons(&self, frame: u64) -> Option<JoypadButton> {
+        self.inputs.iter().find(|(f, _)| *f == frame).map(|(_, buttons)| *buttons)
+    }
+}
+
+pub fn write_ppm<W: Write>(out: &mut W, frame: &Frame) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", Frame::WIDTH, Frame::HIGHT)?;
+    out.write_all(&frame.data)
+}
+
+// RGB24 pixels of a binary PPM (P6) with the size of a frame
+pub fn read_ppm(data: &[u8]) -> Result<Vec<u8>, String> {
+    // magic, width, height and maxval separated by whitespace, then a single whitespace byte
+    let mut fields = vec![];
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < data.len() && (data[pos] as char).is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos < data.len() && data[pos] == b'#' {
+            while pos < data.len() && data[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let begin = pos;
+        while pos < data.len() && !(data[pos] as char).is_ascii_whitespace() {
+            pos += 1;
+        }
+        if begin == pos {
+            return Err(String::from("truncated PPM header"));
+        }
+        fields.push(String::from_utf8_lossy(&data[begin..pos]).into_owned());
+    }
+    let size = format!("{} {}", Frame::WIDTH, Frame::HIGHT);
+    if fields[0] != "P6" || fields[3] != "255" {
+        return Err(String::from("only binary PPM (P6) with 8-bit channels is supported"));
+    }
+    if format!("{} {}", fields[1], fields[2]) != size {
+        return Err(format!("image is {}x{}, expected {}x{}", fields[1], fields[2], Frame::WIDTH, Frame::HIGHT));
+    }
+    if pos >= data.len() {
+        return Err(String::from("truncated PPM data"));
+    }
+    let pixels = &data[pos + 1..];
+    if pixels.len() < Frame::WIDTH * Frame::HIGHT * 3 {
+        return Err(String::from("truncated PPM data"));
+    }
+    Ok(pixels[..Frame::WIDTH * Frame::HIGHT * 3].to_vec())
+}
+
+// share of pixels that differ, in percent
+pub fn diff_percent(actual: &[u8], expected: &[u8]) -> f64 {
+    let pixels = actual.len() / 3;
+    let differing = actual.chunks(3).zip(expected.chunks(3)).filter(|(a, e)| a != e).count();
+    differing as f64 * 100.0 / pixels as f64
+}
+
+// nes-emu verify <file path> <movie> <expected ppm> --frame <n>
+// the expected image has to be a binary PPM, PNG references need converting first
+pub fn run(args: &[String]) -> ! {
+    let usage = "usage: nes-emu verify <file path> <movie> <expected ppm> --frame <n> (binary P6 PPM, 256x240)";
+    let (path, movie_path, expected_path) = match (args.get(2), args.get(3), args.get(4)) {
+        (Some(path), Some(movie), Some(expected)) => (path, movie, expected),
+        _ => {
+            println!("{}", usage);
+            std::process::exit(0);
+        }
+    };
+    let target: u64 = match (args.get(5).map(|s| s.as_str()), args.get(6).and_then(|n| n.parse().ok())) {
+        (Some("--frame"), Some(n)) if n > 0 => n,
+        _ => {
+            println!("{}", usage);
+            std::process::exit(1);
+        }
+    };
+    let movie = match std::fs::read_to_string(movie_path).map_err(|e| e.to_string()).and_then(|text| Movie::parse(&text)) {
+        Ok(movie) => movie,
+        Err(msg) => {
+            println!("{}: {}", movie_path, msg);
+            std::process::exit(1);
+        }
+    };
+    let expected = match std::fs::read(expected_path).map_err(|e| e.to_string()).and_then(|data| read_ppm(&data)) {
+        Ok(pixels) => pixels,
+        Err(msg) => {
+            println!("{}: {}", expected_path, msg);
+            std::process::exit(1);
+        }
+    };
+    let rom = match std::fs::read(path).map_err(|e| e.to_string()).and_then(|raw| ines::Rom::analyze_raw(&raw).map_err(String::from)) {
+        Ok(rom) => rom,
+        Err(msg) => {
+            println!("{}: {}", path, msg);
+            std::process::exit(1);
+        }
+    };
+    if !cartridge::is_supported(rom.mapper) {
+        println!("mapper {} is not supported", rom.mapper);
+        std::process::exit(1);
+    }
+
+    // no video or audio, the target frame is rendered once
+    let captured = Rc::new(RefCell::new(None));
+    let captured_frame = captured.clone();
+    let mut frame_number = 0u64;
+    let bus = memory::Bus::new(rom, move |ppu: &ppu::Ppu, _apu: &mut apu::Apu, joypads: &mut [controller::Joypad; 2]| {
+        frame_number += 1;
+        if frame_number == target {
+            let mut frame = render::frame::Frame::new();
+            render::render(ppu, &mut frame);
+            *captured_frame.borrow_mut() = Some(frame);
+        }
+        if let Some(buttons) = movie.buttons(frame_number) {
+            joypads[0].set_button_status(controller::JoypadButton::all(), false);
+            joypads[0].set_button_status(buttons, true);
+        }
+    });
+    let mut cpu = cpu::Cpu::new(bus);
+    cpu.reset();
+    cpu.run_with_callback(move |cpu| {
+        if cpu.bus.frame_count() as u64 >= target {
+            cpu.halt();
+        }
+    });
+    if cpu.is_jammed() {
+        println!("CPU jammed at ${:04X} before frame {}", cpu.pc, target);
+        std::process::exit(1);
+    }
+
+    let frame = captured.borrow_mut().take().unwrap();
+    let diff = diff_percent(&frame.data, &expected);
+    println!("frame {}: {:.2}% of pixels differ", target, diff);
+    if diff > 0.0 {
+        // shareable alongside the expected image
+        let actual_path = Path::new(expected_path).with_extension("actual.ppm");
+        write_ppm(&mut BufWriter::new(File::create(&actual_path).unwrap()), &frame).unwrap();
+        println!("actual frame written to {}", actual_path.display());
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_movie() {
+        let movie = Movie::parse("1,00000000\n3,00001001\n\n").unwrap();
+        assert_eq!(movie.buttons(1), Some(JoypadButton::empty()));
+        assert_eq!(movie.buttons(2), None);
+        assert_eq!(movie.buttons(3), Some(JoypadButton::from_bits_truncate(0b1001)));
+        assert!(Movie::parse("1;00000000").is_err());
+    }
+
+    #[test]
+    fn test_ppm_diff() {
+        let mut frame = Frame::new();
+        let mut ppm = vec![];
+        write_ppm(&mut ppm, &frame).unwrap();
+        let expected = read_ppm(&ppm).unwrap();
+        assert_eq!(diff_percent(&frame.data, &expected), 0.0);
+
+        for x in 0..64 {
+            frame.set_pixel(x, 0, (1, 2, 3));
+        }
+        assert_eq!(diff_percent(&frame.data, &expected), 64.0 * 100.0 / (256.0 * 240.0));
+
+        assert!(read_ppm(b"P6\n16 16\n255\n").is_err());
+        assert!(read_ppm(b"P6 256").is_err());
+        // nothing after the header
+        assert_eq!(read_ppm(b"P6\n256 240\n255"), Err(String::from("truncated PPM data")));
+    }
+}
@@ -0,0 +1,12 @@
+pub mod sdl;
+pub mod terminal;
+
+use render::frame::Frame;
+
+// where rendered frames go, implemented by each frontend backend
+pub trait VideoSink {
+    fn present(&mut self, frame: &Frame);
+    // size of the output surface, in the backend's own unit (window pixels, terminal cells)
+    fn resize(&mut self, width: u32, height: u32);
+    fn set_title(&mut self, title: &str);
+}
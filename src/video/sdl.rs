@@ -0,0 +1,91 @@
+use render::frame::Frame;
+use render::scale::{self, Filter};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+use sdl2::EventPump;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use video::VideoSink;
+use input::{EventSource, HostEvent};
+
+pub struct SdlVideo<'a> {
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    // frames are upscaled by the core with the filter unless 1, SDL scales the rest
+    prescale: usize,
+    filter: Filter,
+}
+
+impl<'a> SdlVideo<'a> {
+    pub fn new(canvas: Canvas<Window>, creator: &'a TextureCreator<WindowContext>, prescale: usize, filter: Filter) -> Self {
+        let (width, height) = (Frame::WIDTH * prescale, Frame::HIGHT * prescale);
+        let texture = creator
+            .create_texture_target(PixelFormatEnum::RGB24, width as u32, height as u32)
+            .unwrap();
+        SdlVideo {
+            canvas,
+            texture,
+            prescale,
+            filter,
+        }
+    }
+}
+
+impl<'a> VideoSink for SdlVideo<'a> {
+    fn present(&mut self, frame: &Frame) {
+        if self.prescale == 1 {
+            self.texture.update(None, &frame.data, Frame::WIDTH * 3).unwrap();
+        } else {
+            let data = scale::upscale(frame, self.prescale, self.filter);
+            self.texture.update(None, &data, Frame::WIDTH * self.prescale * 3).unwrap();
+        }
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.canvas.window_mut().set_size(width, height).unwrap();
+        self.canvas
+            .set_scale(width as f32 / Frame::WIDTH as f32, height as f32 / Frame::HIGHT as f32)
+            .unwrap();
+    }
+
+    fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+}
+
+// window close and key presses of the SDL window
+pub struct SdlEvents {
+    pump: EventPump,
+}
+
+impl SdlEvents {
+    pub fn new(pump: EventPump) -> Self {
+        SdlEvents { pump }
+    }
+
+    fn convert(event: Event) -> Option<HostEvent<Keycode>> {
+        match event {
+            Event::Quit { .. } => Some(HostEvent::Quit),
+            Event::KeyDown { keycode: Some(key), .. } => Some(HostEvent::KeyDown(key)),
+            Event::KeyUp { keycode: Some(key), .. } => Some(HostEvent::KeyUp(key)),
+            _ => None,
+        }
+    }
+}
+
+impl EventSource<Keycode> for SdlEvents {
+    fn poll(&mut self) -> Vec<HostEvent<Keycode>> {
+        self.pump.poll_iter().filter_map(Self::convert).collect()
+    }
+
+    fn wait(&mut self) -> Vec<HostEvent<Keycode>> {
+        loop {
+            if let Some(event) = Self::convert(self.pump.wait_event()) {
+                return vec![event];
+            }
+        }
+    }
+}
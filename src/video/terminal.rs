@@ -0,0 +1,304 @@
+use render::frame::Frame;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use sdl2::keyboard::Keycode;
+use video::VideoSink;
+use input::{EventSource, HostEvent};
+
+// terminals send no key releases, only the key again while it's held (auto repeat)
+// a key counts as held until its repeats stop: the first repeat comes after the
+// terminal's repeat delay, the next ones follow quickly
+const FIRST_REPEAT_WAIT: Duration = Duration::from_millis(550);
+const REPEAT_WAIT: Duration = Duration::from_millis(120);
+
+// draws frames with ANSI true color escapes, two pixels per character cell
+// using the upper half block (foreground: upper pixel, background: lower pixel)
+pub struct TerminalVideo<W: Write> {
+    out: W,
+    columns: u32,
+    rows: u32,
+}
+
+impl<W: Write> TerminalVideo<W> {
+    pub fn new(out: W) -> Self {
+        TerminalVideo {
+            out,
+            columns: 128,
+            rows: 60,
+        }
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * Frame::WIDTH + x) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+}
+
+impl<W: Write> VideoSink for TerminalVideo<W> {
+    fn present(&mut self, frame: &Frame) {
+        let mut buf = Vec::new();
+        // move the cursor home instead of clearing to avoid flicker
+        buf.extend_from_slice(b"\x1b[H");
+        for row in 0..self.rows as usize {
+            let upper_y = row * 2 * Frame::HIGHT / (self.rows as usize * 2);
+            let lower_y = (row * 2 + 1) * Frame::HIGHT / (self.rows as usize * 2);
+            for col in 0..self.columns as usize {
+                let x = col * Frame::WIDTH / self.columns as usize;
+                let (r1, g1, b1) = Self::pixel(frame, x, upper_y);
+                let (r2, g2, b2) = Self::pixel(frame, x, lower_y);
+                write!(buf, "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", r1, g1, b1, r2, g2, b2).unwrap();
+            }
+            buf.extend_from_slice(b"\x1b[0m\r\n");
+        }
+        self.out.write_all(&buf).unwrap();
+        self.out.flush().unwrap();
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.columns = width.max(1);
+        self.rows = height.max(1);
+    }
+
+    fn set_title(&mut self, title: &str) {
+        write!(self.out, "\x1b]0;{}\x07", title).unwrap();
+    }
+}
+
+// puts the terminal in non-canonical mode without echo or signals so keys arrive one
+// by one (Ctrl-C included), and restores the previous settings when dropped
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    pub fn enter() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+        Ok(RawMode { saved: saved.trim().to_string() })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// keys read from stdin by a background thread, stdin should be in RawMode
+pub struct TerminalEvents {
+    bytes: Receiver<Vec<u8>>,
+    // key, last seen, repeated since it went down
+    held: Vec<(Keycode, Instant, bool)>,
+}
+
+impl TerminalEvents {
+    pub fn new() -> Self {
+        let (sender, bytes) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => if sender.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    },
+                }
+            }
+        });
+        TerminalEvents { bytes, held: vec![] }
+    }
+
+    // key downs for new keys, key ups for keys whose repeats stopped
+    fn update(&mut self, keys: Vec<HostEvent<Keycode>>, now: Instant) -> Vec<HostEvent<Keycode>> {
+        let mut events = vec![];
+        for event in keys {
+            match event {
+                HostEvent::KeyDown(key) => match self.held.iter_mut().find(|h| h.0 == key) {
+                    Some(held) => *held = (key, now, true),
+                    None => {
+                        self.held.push((key, now, false));
+                        events.push(HostEvent::KeyDown(key));
+                    },
+                },
+                other => events.push(other),
+            }
+        }
+        self.held.retain(|&(key, seen, repeated)| {
+            let wait = if repeated { REPEAT_WAIT } else { FIRST_REPEAT_WAIT };
+            if now.duration_since(seen) > wait {
+                events.push(HostEvent::KeyUp(key));
+                false
+            } else {
+                true
+            }
+        });
+        events
+    }
+}
+
+impl EventSource<Keycode> for TerminalEvents {
+    fn poll(&mut self) -> Vec<HostEvent<Keycode>> {
+        let mut keys = vec![];
+        loop {
+            match self.bytes.try_recv() {
+                Ok(bytes) => keys.extend(parse_keys(&bytes)),
+                Err(TryRecvError::Empty) => break,
+                // stdin is closed, nothing can stop the game but a quit
+                Err(TryRecvError::Disconnected) => {
+                    keys.push(HostEvent::Quit);
+                    break;
+                },
+            }
+        }
+        self.update(keys, Instant::now())
+    }
+
+    fn wait(&mut self) -> Vec<HostEvent<Keycode>> {
+        loop {
+            let keys = match self.bytes.recv() {
+                Ok(bytes) => parse_keys(&bytes),
+                Err(_) => vec![HostEvent::Quit],
+            };
+            let events = self.update(keys, Instant::now());
+            if !events.is_empty() {
+                return events;
+            }
+        }
+    }
+}
+
+// key presses in bytes read from the terminal: printable characters, arrows,
+// F1-F12 and Ctrl-C (quit), the rest is dropped
+pub fn parse_keys(bytes: &[u8]) -> Vec<HostEvent<Keycode>> {
+    let mut events = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let key = match bytes[i] {
+            0x03 => {
+                events.push(HostEvent::Quit);
+                i += 1;
+                continue;
+            },
+            0x1b if i + 2 < bytes.len() && (bytes[i + 1] == b'[' || bytes[i + 1] == b'O') => {
+                // CSI or SS3 sequence: parameters, then a final byte
+                let end = (i + 2..bytes.len()).find(|&j| (0x40..=0x7e).contains(&bytes[j])).unwrap_or(bytes.len() - 1);
+                let key = escape_key(&bytes[i + 2..end], bytes[end]);
+                i = end + 1;
+                match key {
+                    Some(key) => key,
+                    None => continue,
+                }
+            },
+            b'\r' | b'\n' => {
+                i += 1;
+                Keycode::Return
+            },
+            0x7f => {
+                i += 1;
+                Keycode::Backspace
+            },
+            byte => {
+                i += 1;
+                // SDL keycodes of printable keys are their lowercase ASCII code
+                match Keycode::from_i32(byte.to_ascii_lowercase() as i32) {
+                    Some(key) => key,
+                    None => continue,
+                }
+            },
+        };
+        events.push(HostEvent::KeyDown(key));
+    }
+    events
+}
+
+fn escape_key(params: &[u8], last: u8) -> Option<Keycode> {
+    match (params, last) {
+        (_, b'A') => Some(Keycode::Up),
+        (_, b'B') => Some(Keycode::Down),
+        (_, b'C') => Some(Keycode::Right),
+        (_, b'D') => Some(Keycode::Left),
+        (_, b'P') => Some(Keycode::F1),
+        (_, b'Q') => Some(Keycode::F2),
+        (_, b'R') => Some(Keycode::F3),
+        (_, b'S') => Some(Keycode::F4),
+        (b"15", b'~') => Some(Keycode::F5),
+        (b"17", b'~') => Some(Keycode::F6),
+        (b"18", b'~') => Some(Keycode::F7),
+        (b"19", b'~') => Some(Keycode::F8),
+        (b"20", b'~') => Some(Keycode::F9),
+        (b"21", b'~') => Some(Keycode::F10),
+        (b"23", b'~') => Some(Keycode::F11),
+        (b"24", b'~') => Some(Keycode::F12),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(parse_keys(b"aS \r\x7f"), vec![
+            HostEvent::KeyDown(Keycode::A),
+            HostEvent::KeyDown(Keycode::S),
+            HostEvent::KeyDown(Keycode::Space),
+            HostEvent::KeyDown(Keycode::Return),
+            HostEvent::KeyDown(Keycode::Backspace),
+        ]);
+        assert_eq!(parse_keys(b"\x1b[A\x1bOD\x1b[24~\x1b"), vec![
+            HostEvent::KeyDown(Keycode::Up),
+            HostEvent::KeyDown(Keycode::Left),
+            HostEvent::KeyDown(Keycode::F12),
+            HostEvent::KeyDown(Keycode::Escape),
+        ]);
+        // unknown sequences are skipped as a whole
+        assert_eq!(parse_keys(b"\x1b[1;5Hp\x03"), vec![HostEvent::KeyDown(Keycode::P), HostEvent::Quit]);
+    }
+
+    #[test]
+    fn test_held_until_repeats_stop() {
+        let (_sender, bytes) = mpsc::channel();
+        let mut events = TerminalEvents { bytes, held: vec![] };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(events.update(parse_keys(b"a"), at(0)), vec![HostEvent::KeyDown(Keycode::A)]);
+        // still waiting for the terminal's first repeat
+        assert!(events.update(vec![], at(500)).is_empty());
+        assert!(events.update(parse_keys(b"a"), at(520)).is_empty());
+        assert!(events.update(parse_keys(b"a"), at(560)).is_empty());
+        assert_eq!(events.update(vec![], at(700)), vec![HostEvent::KeyUp(Keycode::A)]);
+        // a tap is released once no repeat follows
+        events.update(parse_keys(b"b"), at(1000));
+        assert_eq!(events.update(vec![], at(1600)), vec![HostEvent::KeyUp(Keycode::B)]);
+    }
+
+    #[test]
+    fn test_half_block_output() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        frame.set_pixel(0, 1, (4, 5, 6));
+
+        let mut out: Vec<u8> = vec![];
+        {
+            let mut video = TerminalVideo::new(&mut out);
+            video.resize(Frame::WIDTH as u32, Frame::HIGHT as u32 / 2);
+            video.present(&frame);
+        }
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("\x1b[H\x1b[38;2;1;2;3m\x1b[48;2;4;5;6m\u{2580}"));
+        assert_eq!(text.matches("\r\n").count(), Frame::HIGHT / 2);
+        assert_eq!(text.matches('\u{2580}').count(), Frame::WIDTH * Frame::HIGHT / 2);
+    }
+}